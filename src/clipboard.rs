@@ -0,0 +1,182 @@
+//! Bridges the Wayland `wl_data_device` selection protocol to the Qubes
+//! inter-VM clipboard, which the GUI daemon drives through plain `Copy` and
+//! `Paste` events rather than a full data_offer/fd handshake.
+
+use std::{cell::RefCell, os::unix::io::RawFd, rc::Rc};
+
+use smithay::{
+    reexports::wayland_server::protocol::wl_data_source::WlDataSource,
+    wayland::data_device::{default_action_chooser, init_data_device, DataDeviceEvent},
+};
+
+/// The daemon refuses clipboard payloads above this size; truncate rather
+/// than fail outright.
+pub const MAX_CLIPBOARD_BYTES: usize = 64 * 1024;
+
+#[derive(Default)]
+pub struct ClipboardState {
+    /// The VM-local client's current `text/plain` selection source, kept
+    /// around unread and relayed to the daemon only once it actually asks
+    /// for one via `Copy` - the source's pipe is only good for one read, so
+    /// there's no point consuming it any earlier.
+    local_selection: Option<WlDataSource>,
+    /// Bytes most recently handed to us by the daemon in a `Paste` event.
+    /// Kept under the "untrusted" name throughout since they crossed a VM
+    /// boundary. They are written out only when smithay reports a
+    /// `SendSelection` request; nothing in this module sets them as the
+    /// seat's selection, so no `wl_data_offer` is made for them here.
+    untrusted_incoming: Option<Vec<u8>>,
+}
+
+impl ClipboardState {
+    pub fn set_local_selection(&mut self, source: Option<WlDataSource>) {
+        self.local_selection = source;
+    }
+
+    pub fn local_selection(&self) -> Option<&WlDataSource> {
+        self.local_selection.as_ref()
+    }
+
+    /// Record a `Paste` reply from the daemon, to be written out on the next
+    /// `SendSelection` request (see `untrusted_incoming`).
+    pub fn set_untrusted_incoming(&mut self, untrusted_data: Vec<u8>) {
+        self.untrusted_incoming = Some(untrusted_data);
+    }
+
+    pub fn untrusted_incoming(&self) -> Option<&[u8]> {
+        self.untrusted_incoming.as_deref()
+    }
+}
+
+pub fn init_clipboard(
+    display: &mut smithay::reexports::wayland_server::Display,
+    log: ::slog::Logger,
+) -> Rc<RefCell<ClipboardState>> {
+    let state = Rc::new(RefCell::new(ClipboardState::default()));
+    init_data_device(
+        display,
+        {
+            let state = state.clone();
+            let log = log.clone();
+            move |event| match event {
+                DataDeviceEvent::NewSelection(Some(source)) => {
+                    // Don't read the data now: the source's pipe is only good
+                    // for one read, so wait for an actual `Copy` request from
+                    // the daemon before consuming it.
+                    if source.mime_types().iter().any(|mime| mime == "text/plain") {
+                        debug!(log, "Client offered a new text/plain selection");
+                        state.borrow_mut().set_local_selection(Some(source));
+                    } else {
+                        state.borrow_mut().set_local_selection(None);
+                    }
+                }
+                DataDeviceEvent::NewSelection(None) => {
+                    state.borrow_mut().set_local_selection(None);
+                }
+                DataDeviceEvent::SendSelection { mime_type, fd } => {
+                    send_untrusted_incoming(&state, &mime_type, fd, &log);
+                }
+                _ => {}
+            }
+        },
+        default_action_chooser,
+        log,
+    );
+    state
+}
+
+fn send_untrusted_incoming(state: &Rc<RefCell<ClipboardState>>, mime_type: &str, fd: RawFd, log: &::slog::Logger) {
+    if mime_type != "text/plain" {
+        return;
+    }
+    match state.borrow().untrusted_incoming() {
+        Some(data) => {
+            if let Err(e) = nix::unistd::write(fd, data) {
+                warn!(log, "Failed writing clipboard data back to client"; "error" => ?e);
+            }
+        }
+        None => debug!(log, "Client asked for our selection, but nothing is on the clipboard"),
+    }
+    let _ = nix::unistd::close(fd);
+}
+
+/// Forward the VM-local selection to the GUI daemon in response to its
+/// `Copy` request, capping the payload as the Qubes clipboard does.
+pub fn offer_selection_to_daemon(
+    agent: &mut qubes_gui_client::Client,
+    window: std::num::NonZeroU32,
+    state: &Rc<RefCell<ClipboardState>>,
+    log: &::slog::Logger,
+) -> std::io::Result<()> {
+    let data = match state.borrow().local_selection() {
+        Some(source) => read_local_selection(source, log),
+        None => Vec::new(),
+    };
+    let len = data.len().min(MAX_CLIPBOARD_BYTES);
+    agent.send_raw(&data[..len], window, qubes_gui::MSG_CLIPBOARD_DATA)
+}
+
+/// How long to wait for the next chunk of the client's selection before
+/// giving up on it: this runs synchronously on the single event-loop thread,
+/// so a client that never finishes writing (malicious or just slow) must not
+/// be able to freeze the whole compositor indefinitely.
+const READ_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Ask the client holding `source` to write its `text/plain` selection into
+/// a pipe, and read back whatever it sends - the same fd-handoff dance
+/// `send_untrusted_incoming` does in the other direction for a `Paste`.
+/// Bounded both in size (stops at `MAX_CLIPBOARD_BYTES`) and in time (each
+/// chunk is awaited with `poll()` under `READ_TIMEOUT`, rather than a plain
+/// blocking read that could wait forever).
+fn read_local_selection(source: &WlDataSource, log: &::slog::Logger) -> Vec<u8> {
+    let (read_fd, write_fd) = match nix::unistd::pipe() {
+        Ok(fds) => fds,
+        Err(e) => {
+            warn!(log, "Failed creating pipe for clipboard read"; "error" => ?e);
+            return Vec::new();
+        }
+    };
+    source.send("text/plain".into(), write_fd);
+    let _ = nix::unistd::close(write_fd);
+
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    while data.len() < MAX_CLIPBOARD_BYTES {
+        let mut fds = [nix::poll::PollFd::new(read_fd, nix::poll::PollFlags::POLLIN)];
+        match nix::poll::poll(&mut fds, READ_TIMEOUT.as_millis() as i32) {
+            Ok(0) => {
+                warn!(log, "Timed out waiting for the client's clipboard data");
+                break;
+            }
+            Ok(_) => {}
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => {
+                warn!(log, "poll() failed while reading clipboard data"; "error" => ?e);
+                break;
+            }
+        }
+        let want = buf.len().min(MAX_CLIPBOARD_BYTES - data.len());
+        match nix::unistd::read(read_fd, &mut buf[..want]) {
+            Ok(0) => break, // EOF: the client closed its end
+            Ok(n) => data.extend_from_slice(&buf[..n]),
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(e) => {
+                warn!(log, "Failed reading clipboard data from client"; "error" => ?e);
+                break;
+            }
+        }
+    }
+    let _ = nix::unistd::close(read_fd);
+    data
+}
+
+/// Store the daemon-provided clipboard, truncated to `MAX_CLIPBOARD_BYTES`,
+/// to be written to the next client `SendSelection` request for
+/// `text/plain`. This does not set the seat's selection, so clients are not
+/// sent a new `wl_data_offer` for it.
+pub fn accept_incoming_from_daemon(state: &Rc<RefCell<ClipboardState>>, untrusted_data: Vec<u8>) {
+    let len = untrusted_data.len().min(MAX_CLIPBOARD_BYTES);
+    state
+        .borrow_mut()
+        .set_untrusted_incoming(untrusted_data[..len].to_vec());
+}
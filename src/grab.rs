@@ -0,0 +1,187 @@
+//! Interactive move/resize grabs started from `xdg_toplevel.move`/`.resize`.
+//!
+//! Unlike anvil, which repositions a window in its own compositor-side space,
+//! we have to keep the Qubes GUI daemon in sync too: every motion event
+//! during the grab is relayed as a `qubes_gui::Configure` through the same
+//! path the daemon's own resize/move notifications use.
+
+use std::{cell::RefCell, num::NonZeroU32, rc::Rc};
+
+use smithay::{
+    reexports::wayland_server::protocol::{wl_pointer::ButtonState, wl_surface::WlSurface},
+    utils::{Logical, Point},
+    wayland::{
+        seat::{AxisFrame, GrabStartData, PointerGrab, PointerInnerHandle},
+        shell::xdg::ToplevelSurface,
+        Serial,
+    },
+};
+
+use crate::qubes::QubesData;
+
+pub struct MoveSurfaceGrab {
+    pub start_data: GrabStartData,
+    pub window: NonZeroU32,
+    pub backend_data: Rc<RefCell<QubesData>>,
+    pub initial_window_location: Point<i32, Logical>,
+    /// The surface's current size, captured once at grab start: a pure move
+    /// never changes it, so there is no need to re-derive it every motion
+    /// event.
+    pub window_size: qubes_gui::WindowSize,
+}
+
+impl PointerGrab for MoveSurfaceGrab {
+    fn motion(
+        &mut self,
+        _handle: &mut PointerInnerHandle<'_>,
+        location: Point<f64, Logical>,
+        _focus: Option<(WlSurface, Point<i32, Logical>)>,
+        _serial: Serial,
+        _time: u32,
+    ) {
+        let delta = location - self.start_data.location;
+        let new_x = (self.initial_window_location.x as f64 + delta.x).round() as i32;
+        let new_y = (self.initial_window_location.y as f64 + delta.y).round() as i32;
+        if !self.backend_data.borrow().map.contains_key(&self.window) {
+            return;
+        }
+        self.backend_data
+            .borrow_mut()
+            .process_client_configure(
+                qubes_gui::Configure {
+                    rectangle: qubes_gui::Rectangle {
+                        top_left: qubes_gui::Coordinates {
+                            x: new_x.max(0) as u32,
+                            y: new_y.max(0) as u32,
+                        },
+                        size: self.window_size,
+                    },
+                    override_redirect: 0,
+                },
+                self.window,
+            )
+            .ok();
+    }
+
+    fn button(
+        &mut self,
+        handle: &mut PointerInnerHandle<'_>,
+        button: u32,
+        state: ButtonState,
+        serial: Serial,
+        time: u32,
+    ) {
+        handle.button(button, state, serial, time);
+        // The grab ends once every button involved in starting it is
+        // released, matching anvil's behavior.
+        if handle.current_pressed().is_empty() {
+            handle.unset_grab(serial, time);
+        }
+    }
+
+    fn axis(&mut self, handle: &mut PointerInnerHandle<'_>, details: AxisFrame) {
+        handle.axis(details)
+    }
+
+    fn start_data(&self) -> &GrabStartData {
+        &self.start_data
+    }
+}
+
+pub struct ResizeSurfaceGrab {
+    pub start_data: GrabStartData,
+    pub toplevel: ToplevelSurface,
+    pub window: NonZeroU32,
+    pub backend_data: Rc<RefCell<QubesData>>,
+    pub edges: smithay::wayland::shell::xdg::ResizeEdge,
+    pub initial_window_size: smithay::utils::Size<i32, Logical>,
+    pub initial_window_location: Point<i32, Logical>,
+}
+
+impl PointerGrab for ResizeSurfaceGrab {
+    fn motion(
+        &mut self,
+        _handle: &mut PointerInnerHandle<'_>,
+        location: Point<f64, Logical>,
+        _focus: Option<(WlSurface, Point<i32, Logical>)>,
+        _serial: Serial,
+        _time: u32,
+    ) {
+        use smithay::wayland::shell::xdg::ResizeEdge;
+
+        let delta = location - self.start_data.location;
+        let mut new_width = self.initial_window_size.w;
+        let mut new_height = self.initial_window_size.h;
+        let mut new_x = self.initial_window_location.x;
+        let mut new_y = self.initial_window_location.y;
+
+        if self.edges.intersects(ResizeEdge::LEFT) {
+            new_width = (self.initial_window_size.w as f64 - delta.x).round() as i32;
+            new_x = self.initial_window_location.x + (self.initial_window_size.w - new_width);
+        } else if self.edges.intersects(ResizeEdge::RIGHT) {
+            new_width = (self.initial_window_size.w as f64 + delta.x).round() as i32;
+        }
+        if self.edges.intersects(ResizeEdge::TOP) {
+            new_height = (self.initial_window_size.h as f64 - delta.y).round() as i32;
+            new_y = self.initial_window_location.y + (self.initial_window_size.h - new_height);
+        } else if self.edges.intersects(ResizeEdge::BOTTOM) {
+            new_height = (self.initial_window_size.h as f64 + delta.y).round() as i32;
+        }
+
+        let new_width = new_width.max(1);
+        let new_height = new_height.max(1);
+
+        let _ = self.toplevel.with_pending_state(|state| {
+            state.size = Some((new_width, new_height).into());
+        });
+        self.toplevel.send_configure();
+
+        self.backend_data
+            .borrow_mut()
+            .process_client_configure(
+                qubes_gui::Configure {
+                    rectangle: qubes_gui::Rectangle {
+                        top_left: qubes_gui::Coordinates {
+                            x: new_x.max(0) as u32,
+                            y: new_y.max(0) as u32,
+                        },
+                        size: qubes_gui::WindowSize {
+                            width: new_width as u32,
+                            height: new_height as u32,
+                        },
+                    },
+                    override_redirect: 0,
+                },
+                self.window,
+            )
+            .ok();
+    }
+
+    fn button(
+        &mut self,
+        handle: &mut PointerInnerHandle<'_>,
+        button: u32,
+        state: ButtonState,
+        serial: Serial,
+        time: u32,
+    ) {
+        handle.button(button, state, serial, time);
+        if handle.current_pressed().is_empty() {
+            handle.unset_grab(serial, time);
+            let _ = self.toplevel.with_pending_state(|state| {
+                state.states.unset(
+                    smithay::reexports::wayland_protocols::xdg_shell::server::xdg_toplevel::State::Resizing,
+                );
+            });
+            self.toplevel.send_configure();
+        }
+    }
+
+    fn axis(&mut self, handle: &mut PointerInnerHandle<'_>, details: AxisFrame) {
+        handle.axis(details)
+    }
+
+    fn start_data(&self) -> &GrabStartData {
+        &self.start_data
+    }
+}
@@ -0,0 +1,52 @@
+//! The single `wl_output` the agent advertises, shared by every Qubes window
+//! and sized to whatever the GUI daemon currently reports as the screen.
+
+use smithay::{
+    reexports::wayland_server::{protocol::wl_output, Display},
+    wayland::output::{Mode, Output, PhysicalProperties},
+};
+
+/// Wraps the `wl_output` global together with the `Mode` currently advertised,
+/// so resizes can be applied as a diff against the old mode.
+pub struct QubesOutput {
+    pub output: Output,
+    mode: Mode,
+}
+
+impl QubesOutput {
+    pub fn new(display: &mut Display, width: i32, height: i32, scale: i32, log: ::slog::Logger) -> Self {
+        let mode = Mode {
+            size: (width, height).into(),
+            refresh: 60_000,
+        };
+        let (output, _global) = Output::new(
+            display,
+            super::qubes::OUTPUT_NAME.to_owned(),
+            PhysicalProperties {
+                size: (0, 0).into(),
+                subpixel: wl_output::Subpixel::Unknown,
+                make: "Qubes".into(),
+                model: "Qubes virtual display".into(),
+            },
+            log,
+        );
+        output.change_current_state(Some(mode), None, Some(scale), None);
+        output.set_preferred(mode);
+        Self { output, mode }
+    }
+
+    /// Update the advertised mode after the daemon resizes the screen.
+    pub fn resize(&mut self, width: i32, height: i32) {
+        let mode = Mode {
+            size: (width, height).into(),
+            refresh: 60_000,
+        };
+        if mode == self.mode {
+            return;
+        }
+        self.output.delete_mode(self.mode);
+        self.output.change_current_state(Some(mode), None, None, None);
+        self.output.set_preferred(mode);
+        self.mode = mode;
+    }
+}
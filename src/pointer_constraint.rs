@@ -0,0 +1,96 @@
+//! Pointer locking and confinement (`zwp_pointer_constraints_v1`), with
+//! relative motion delivered through `zwp_relative_pointer_v1` while a lock
+//! is active. Games and remote-desktop-style clients need this to capture
+//! the mouse instead of fighting the daemon's absolute coordinates; image
+//! viewers and the like use confinement to keep drag gestures from leaving
+//! the surface they started on.
+
+use smithay::{
+    reexports::wayland_server::{protocol::wl_surface::WlSurface, Display},
+    wayland::{
+        pointer_constraints::{init_pointer_constraints_global, with_pointer_constraint},
+        relative_pointer::{init_relative_pointer_manager_global, RelativeMotionEvent},
+        seat::PointerHandle,
+    },
+};
+
+/// The daemon only ever reports absolute screen coordinates, so turning
+/// those into the deltas a locked client expects means remembering the last
+/// sample we saw. Cleared whenever the lock isn't active, so a freshly
+/// (re-)established lock doesn't start with a spurious jump.
+#[derive(Default)]
+pub struct PointerConstraintState {
+    last_seen: Option<qubes_gui::Coordinates>,
+    /// The locked surface's most recently requested
+    /// `set_cursor_position_hint`, surface-local. Refreshed on every motion
+    /// event while a lock is active and consumed the moment it's released, so the
+    /// client's requested "where the pointer should appear once unlocked"
+    /// is honored even though the hint request and the unlock itself
+    /// arrive as separate, unordered protocol requests.
+    cursor_position_hint: Option<(f64, f64)>,
+}
+
+impl PointerConstraintState {
+    pub fn last_seen(&self) -> Option<qubes_gui::Coordinates> {
+        self.last_seen
+    }
+
+    pub fn set_last_seen(&mut self, coordinates: Option<qubes_gui::Coordinates>) {
+        self.last_seen = coordinates;
+    }
+
+    pub fn set_cursor_position_hint(&mut self, hint: Option<(f64, f64)>) {
+        if hint.is_some() {
+            self.cursor_position_hint = hint;
+        }
+    }
+
+    /// Take and clear the last hint seen, so it's only ever applied once.
+    pub fn take_cursor_position_hint(&mut self) -> Option<(f64, f64)> {
+        self.cursor_position_hint.take()
+    }
+}
+
+pub fn init_pointer_constraints(display: &mut Display, log: ::slog::Logger) {
+    init_pointer_constraints_global(display, log.clone());
+    init_relative_pointer_manager_global(display, log);
+}
+
+/// Whether `surface` currently holds an active lock, per smithay's own
+/// bookkeeping (it only activates a constraint once the surface has pointer
+/// focus, matching what the protocol requires).
+pub fn surface_is_locked(surface: &WlSurface, pointer: &PointerHandle) -> bool {
+    with_pointer_constraint(surface, pointer, |constraint| {
+        constraint.map(|c| c.is_active() && c.is_locked()).unwrap_or(false)
+    })
+}
+
+/// Whether `surface` currently holds an active confinement (the other kind
+/// of constraint the protocol defines, where the pointer keeps moving but
+/// may not leave the surface).
+pub fn surface_is_confined(surface: &WlSurface, pointer: &PointerHandle) -> bool {
+    with_pointer_constraint(surface, pointer, |constraint| {
+        constraint.map(|c| c.is_active() && !c.is_locked()).unwrap_or(false)
+    })
+}
+
+/// The position, surface-local, that a locked client most recently asked
+/// (via `zwp_locked_pointer_v1.set_cursor_position_hint`) to have the
+/// pointer warped to once the lock is released. `None` if the client never
+/// sent a hint for the currently-active lock.
+pub fn cursor_position_hint(surface: &WlSurface, pointer: &PointerHandle) -> Option<(f64, f64)> {
+    with_pointer_constraint(surface, pointer, |constraint| {
+        constraint.and_then(|c| c.cursor_position_hint())
+    })
+}
+
+/// Deliver one relative-motion sample to the locked surface's
+/// `zwp_relative_pointer_v1` resource, instead of moving the (suppressed)
+/// absolute pointer.
+pub fn send_relative_motion(pointer: &PointerHandle, dx: f64, dy: f64, time: u32) {
+    pointer.relative_motion(&RelativeMotionEvent {
+        delta: (dx, dy).into(),
+        delta_unaccel: (dx, dy).into(),
+        utime: time as u64,
+    });
+}
@@ -1,6 +1,6 @@
 use std::{
     cell::RefCell, collections::BTreeMap, convert::TryInto, num::NonZeroU32, os::unix::io::AsRawFd,
-    rc::Rc, sync::atomic::Ordering, sync::Mutex, task::Poll, time::Duration,
+    os::unix::process::CommandExt, rc::Rc, sync::atomic::Ordering, task::Poll, time::Duration,
 };
 
 use qubes_gui_agent_proto::DaemonToAgentEvent;
@@ -21,7 +21,7 @@ use smithay::{
     wayland::{
         compositor::{with_states, SurfaceAttributes},
         seat::{AxisFrame, FilterResult},
-        shell::xdg,
+        shell::{legacy, xdg},
         SERIAL_COUNTER,
     },
 };
@@ -42,6 +42,15 @@ pub struct QubesData {
     pub map: BTreeMap<NonZeroU32, QubesBackendData>,
     pub log: slog::Logger,
     buf: qubes_gui_gntalloc::Buffer,
+    pub output: crate::output::QubesOutput,
+    pub clipboard: Rc<RefCell<crate::clipboard::ClipboardState>>,
+    pub pointer_constraint: Rc<RefCell<crate::pointer_constraint::PointerConstraintState>>,
+    pub xdg_activation: Rc<RefCell<crate::xdg_activation::XdgActivationState>>,
+    /// Which X11 keycodes (0..256, the same indexing as the daemon's keymap
+    /// bitmap) we currently believe are held down, so a `Keymap` resync can
+    /// be turned into the minimal set of press/release transitions instead
+    /// of replaying all 256 keys.
+    pressed_keys: [bool; 256],
 }
 
 /// Surface kinds
@@ -50,6 +59,14 @@ pub enum Kind {
     Toplevel(xdg::ToplevelSurface),
     /// Popup
     Popup(xdg::PopupSurface),
+    /// Legacy wl_shell surface, kept around for clients too old to speak xdg_shell
+    Legacy(legacy::ShellSurface),
+    /// A rootless XWayland window. Unlike the other variants this has no
+    /// xdg_shell/wl_shell role object backing it - X11 clients get neither -
+    /// so the toplevel/popup-ish semantics and the actual configure/close
+    /// requests are relayed through the WM connection in `crate::xwayland`.
+    #[cfg(feature = "xwayland")]
+    X11(crate::xwayland::X11Surface),
 }
 
 impl Kind {
@@ -57,6 +74,23 @@ impl Kind {
         match self {
             Self::Toplevel(t) => t.send_configure(),
             Self::Popup(t) => drop(t.send_configure()),
+            Self::Legacy(t) => {
+                if let Some(surface) = t.get_surface() {
+                    let size = with_states(surface, |data| {
+                        data.data_map
+                            .get::<RefCell<SurfaceData>>()
+                            .and_then(|d| d.borrow().size())
+                    })
+                    .ok()
+                    .flatten()
+                    .unwrap_or_else(|| (1, 1).into());
+                    // wl_shell has no notion of resize edges outside of an
+                    // interactive grab, so we always report "no edge".
+                    t.send_configure(0, size.w, size.h);
+                }
+            }
+            #[cfg(feature = "xwayland")]
+            Self::X11(x11) => x11.send_configure(),
         }
     }
 
@@ -64,6 +98,10 @@ impl Kind {
         match self {
             Self::Toplevel(t) => t.send_close(),
             Self::Popup(t) => t.send_popup_done(),
+            // wl_shell has no close request; the client owns its own lifetime.
+            Self::Legacy(_) => {}
+            #[cfg(feature = "xwayland")]
+            Self::X11(x11) => x11.send_close(),
         }
     }
 
@@ -71,6 +109,29 @@ impl Kind {
         match self {
             Self::Toplevel(t) => t.get_surface(),
             Self::Popup(t) => t.get_surface(),
+            Self::Legacy(t) => t.get_surface(),
+            // `None` until the `WL_SURFACE_ID` handshake in `crate::xwayland`
+            // pairs this window with the wl_surface its client creates for
+            // it; see that module's doc comment.
+            #[cfg(feature = "xwayland")]
+            Self::X11(x11) => x11.get_surface(),
+        }
+    }
+
+    /// Whether the redraw timer's sweep should treat this window as gone.
+    /// For every other `Kind`, `get_surface() == None` means exactly that -
+    /// their only handle on liveness is whether the role object's wl_surface
+    /// resource still exists. X11 windows are different: a freshly-mapped
+    /// one legitimately has no paired wl_surface yet (the `WL_SURFACE_ID`
+    /// handshake hasn't arrived), and their actual destruction is already
+    /// reaped precisely, via `UnmapNotify`/`DestroyNotify` in
+    /// `crate::xwayland` calling `reap_window` directly - so the sweep has
+    /// nothing to check here.
+    fn is_dead(&self) -> bool {
+        match self {
+            #[cfg(feature = "xwayland")]
+            Self::X11(_) => false,
+            other => other.get_surface().is_none(),
         }
     }
 
@@ -78,6 +139,13 @@ impl Kind {
         match self {
             Self::Toplevel(t) => t.client(),
             Self::Popup(t) => t.client(),
+            // Legacy clients are pinged through a different shell client type;
+            // nothing currently consumes this path for them.
+            Self::Legacy(_) => None,
+            // X11 clients have no xdg_shell client object either; pings go
+            // through the WM connection instead, and nothing needs that yet.
+            #[cfg(feature = "xwayland")]
+            Self::X11(_) => None,
         }
     }
 }
@@ -89,6 +157,13 @@ pub struct QubesBackendData {
     pub has_configured: bool,
     /// The coordinates of the surface
     pub coordinates: Point<i32, Logical>,
+    /// Set by a commit that actually pushed new damage to the daemon; the
+    /// redraw timer only services (and clears) windows with this set, rather
+    /// than draining every window's frame callbacks on every tick.
+    pub needs_frame: bool,
+    /// When we last serviced this window's frame callbacks, kept around for
+    /// diagnosing stalled clients.
+    pub last_presented: Option<std::time::Instant>,
 }
 
 impl QubesData {
@@ -110,6 +185,19 @@ impl QubesData {
             window,
             buffer_swapped: false,
             coordinates: Default::default(),
+            pre_fullscreen_size: None,
+            minimized: false,
+            last_title: None,
+        }
+    }
+
+    /// The Qubes screen geometry, tracked off the size the daemon configures
+    /// our own window (id 1) to, the same way anvil derives a fullscreen
+    /// output rectangle from the output's current mode.
+    pub fn screen_size(&self) -> qubes_gui::WindowSize {
+        qubes_gui::WindowSize {
+            width: self.last_width,
+            height: self.last_height,
         }
     }
 
@@ -123,7 +211,7 @@ impl QubesData {
         }
     }
 
-    fn process_client_configure(
+    pub(crate) fn process_client_configure(
         &mut self,
         m: qubes_gui::Configure,
         window: NonZeroU32,
@@ -137,6 +225,7 @@ impl QubesData {
             surface,
             ref mut has_configured,
             ref mut coordinates,
+            ..
         } = match data {
             None => return Ok(()),
             Some(data) => data,
@@ -157,6 +246,26 @@ impl QubesData {
                 }
             })
         });
+        // X11 windows have no `with_pending_state`/`send_configure` role
+        // object to drive like the arms below; note the size change and
+        // push it straight to the X11 window through the WM connection.
+        #[cfg(feature = "xwayland")]
+        if let Kind::X11(x11) = surface {
+            let unchanged = x11.note_configure(width as i32, height as i32);
+            if unchanged && *has_configured {
+                debug!(self.log, "Ignoring configure event that didn’t change size");
+            } else {
+                trace!(
+                    self.log,
+                    "Sending configure event to X11 client: width {}, height {}",
+                    width,
+                    height,
+                );
+                surface.send_configure();
+                *has_configured = true;
+            }
+            return Ok(());
+        }
         match match surface {
             Kind::Toplevel(surface) => surface.with_pending_state(|state| {
                 let new_size = Some(
@@ -202,6 +311,20 @@ impl QubesData {
         Ok(())
     }
 
+    /// Destroy a Qubes GUI window and drop its tracking entry. Used by the
+    /// redraw timer's sweep for windows whose underlying surface has gone
+    /// away, and (under the `xwayland` feature) by the XWayland WM when an
+    /// X11 client unmaps or destroys a window out from under us.
+    pub fn reap_window(&mut self, window: NonZeroU32) {
+        trace!(self.log, "Destroying window"; "window" => u32::from(window));
+        self.agent.send(&qubes_gui::Destroy {}, window).unwrap();
+        let _: QubesBackendData = self
+            .map
+            .remove(&window)
+            .expect("caller ensures this window is tracked; qed");
+        trace!(self.log, "Destruct successful"; "window" => u32::from(window));
+    }
+
     fn process_self_configure(
         &mut self,
         m: qubes_gui::Configure,
@@ -212,6 +335,7 @@ impl QubesData {
             // no redraw needed
             return Ok(());
         }
+        self.output.resize(width as i32, height as i32);
         let mut need_dump = false;
         if self.last_width * self.last_height != width * height {
             drop(std::mem::replace(
@@ -298,6 +422,16 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
         )
         .unwrap();
     let raw_fd = agent.as_raw_fd();
+    let output = crate::output::QubesOutput::new(
+        &mut *display.borrow_mut(),
+        width as i32,
+        height as i32,
+        1,
+        log.clone(),
+    );
+    let clipboard = crate::clipboard::init_clipboard(&mut *display.borrow_mut(), log.clone());
+    crate::pointer_constraint::init_pointer_constraints(&mut *display.borrow_mut(), log.clone());
+    let xdg_activation = crate::xdg_activation::init_xdg_activation(&mut *display.borrow_mut(), log.clone());
     let data = QubesData {
         agent,
         connection,
@@ -307,6 +441,11 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
         last_width: 0,
         last_height: 0,
         buf,
+        output,
+        clipboard,
+        pointer_constraint: Rc::new(RefCell::new(crate::pointer_constraint::PointerConstraintState::default())),
+        xdg_activation,
+        pressed_keys: [false; 256],
     };
     let mut state = AnvilState::init(
         display.clone(),
@@ -332,7 +471,7 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
                     panic!("No readiness?");
                     // return Ok(calloop::PostAction::Continue);
                 }
-                let ref mut qubes = agent_full.backend_data.borrow_mut();
+                let mut qubes = agent_full.backend_data.borrow_mut();
                 qubes.agent.wait();
                 loop {
                     let (window, ev) = loop {
@@ -383,19 +522,98 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
                                         }).ok().map(|()| (s.clone(), surface.coordinates))
                                     })
                             });
-                            let location = (event.coordinates.x.into(), event.coordinates.y.into()).into();
+                            let mut location: Point<f64, Logical> =
+                                (event.coordinates.x.into(), event.coordinates.y.into()).into();
+                            let locked = focus.as_ref().map_or(false, |(wl_surface, _)| {
+                                crate::pointer_constraint::surface_is_locked(wl_surface, &agent_full.pointer)
+                            });
+                            let was_locked = qubes.pointer_constraint.borrow().last_seen().is_some();
+                            if locked {
+                                let last_seen = qubes.pointer_constraint.borrow().last_seen();
+                                if let Some(last) = last_seen {
+                                    let dx = event.coordinates.x as f64 - last.x as f64;
+                                    let dy = event.coordinates.y as f64 - last.y as f64;
+                                    trace!(
+                                        agent_full.log,
+                                        "Relative motion while locked";
+                                        "dx" => dx, "dy" => dy, "window" => window,
+                                    );
+                                    crate::pointer_constraint::send_relative_motion(
+                                        &agent_full.pointer,
+                                        dx,
+                                        dy,
+                                        time_spent,
+                                    );
+                                }
+                                qubes.pointer_constraint.borrow_mut().set_last_seen(Some(event.coordinates));
+                                if let Some((wl_surface, _)) = &focus {
+                                    qubes.pointer_constraint.borrow_mut().set_cursor_position_hint(
+                                        crate::pointer_constraint::cursor_position_hint(
+                                            wl_surface,
+                                            &agent_full.pointer,
+                                        ),
+                                    );
+                                }
+                                continue;
+                            }
+                            qubes.pointer_constraint.borrow_mut().set_last_seen(None);
+                            // The daemon doesn't let us warp the real, dom0-owned
+                            // cursor, but the lock just released, so our own
+                            // notion of where the pointer is should jump to
+                            // whatever position the client asked for via
+                            // `set_cursor_position_hint` rather than wherever
+                            // the daemon's absolute coordinates last pointed
+                            // while the lock was suppressing them.
+                            if was_locked {
+                                if let Some((_, surf_coords)) = &focus {
+                                    if let Some((hint_x, hint_y)) =
+                                        qubes.pointer_constraint.borrow_mut().take_cursor_position_hint()
+                                    {
+                                        location.x = surf_coords.x as f64 + hint_x;
+                                        location.y = surf_coords.y as f64 + hint_y;
+                                    }
+                                }
+                            }
+                            if let Some((wl_surface, surf_coords)) = &focus {
+                                if crate::pointer_constraint::surface_is_confined(wl_surface, &agent_full.pointer) {
+                                    let size = with_states(wl_surface, |data| {
+                                        data.data_map
+                                            .get::<RefCell<SurfaceData>>()
+                                            .unwrap()
+                                            .borrow()
+                                            .size()
+                                    })
+                                    .ok()
+                                    .flatten();
+                                    if let Some(size) = size {
+                                        let min_x = surf_coords.x as f64;
+                                        let min_y = surf_coords.y as f64;
+                                        location.x = location.x.clamp(min_x, min_x + size.w as f64);
+                                        location.y = location.y.clamp(min_y, min_y + size.h as f64);
+                                    }
+                                }
+                            }
                             trace!(
                                 agent_full.log,
                                 "Motion event";
                                 "location" => ?location,
                                 "window" => window,
                             );
+                            // `pointer.motion()` synchronously dispatches into
+                            // whatever `PointerGrab` is active, and both
+                            // `MoveSurfaceGrab`/`ResizeSurfaceGrab` re-borrow
+                            // this same `Rc<RefCell<QubesData>>` - so the
+                            // borrow above must be released before this call,
+                            // or an in-progress drag panics with
+                            // `already borrowed`.
+                            drop(qubes);
                             agent_full.pointer.motion(
                                 location,
                                 focus,
                                 SERIAL_COUNTER.next_serial(),
                                 time_spent,
-                            )
+                            );
+                            qubes = agent_full.backend_data.borrow_mut();
                         }
                         DaemonToAgentEvent::Crossing { event } => {
                             trace!(agent_full.log,
@@ -438,6 +656,9 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
                                     continue
                                 }
                             };
+                            if let Some(tracked) = qubes.pressed_keys.get_mut(event.keycode as usize) {
+                                *tracked = state == KeyState::Pressed;
+                            }
                             agent_full.keyboard.input::<(), _>(
                                 event.keycode - 8,
                                 state,
@@ -470,14 +691,30 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
                             // info!(agent_full.log, "Sending button event: {:?}", event);
                             match event.button {
                                 4|5|6|7 => {
-                                    let frame = AxisFrame::new(time_spent)
-                                        .source(AxisSource::Wheel);
-                                    let frame = match event.button {
-                                        4 => frame.value(Axis::VerticalScroll, -10f64),
-                                        5 => frame.value(Axis::VerticalScroll, 10f64),
-                                        6 => frame.value(Axis::HorizontalScroll, -10f64),
-                                        7 => frame.value(Axis::HorizontalScroll, 10f64),
-                                        _ => unreachable!(),
+                                    // The Qubes GUI protocol mirrors X11's synthetic
+                                    // button4-7 wheel events: there is no device field
+                                    // to tell a touchpad from a wheel, so the source is
+                                    // always Wheel. What we *can* fix is resolution: the
+                                    // daemon sends one press+release pair per physical
+                                    // click, so report that click as a proper discrete
+                                    // step with v120 data instead of a flat ±10, and let
+                                    // the release terminate the frame.
+                                    let axis = match event.button {
+                                        4 | 5 => Axis::VerticalScroll,
+                                        _ => Axis::HorizontalScroll,
+                                    };
+                                    let direction: i32 = match event.button {
+                                        4 | 6 => -1,
+                                        _ => 1,
+                                    };
+                                    let frame = AxisFrame::new(time_spent).source(AxisSource::Wheel);
+                                    let frame = if state == ButtonState::Pressed {
+                                        frame
+                                            .discrete(axis, direction)
+                                            .amount_v120(axis, direction * 120)
+                                            .value(axis, (direction * 15) as f64)
+                                    } else {
+                                        frame.stop(axis)
                                     };
                                     agent_full.pointer.axis(frame)
                                 }
@@ -499,23 +736,43 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
                             }
                         }
                         DaemonToAgentEvent::Copy => {
-                            trace!(agent_full.log, "clipboard data requested!")
+                            trace!(agent_full.log, "clipboard data requested!");
+                            crate::clipboard::offer_selection_to_daemon(
+                                &mut qubes.agent,
+                                window.try_into().expect("Copy event for window 0?"),
+                                &qubes.clipboard,
+                                &qubes.log,
+                            )?
                         }
-                        DaemonToAgentEvent::Paste { untrusted_data: _ } => {
-                            trace!(agent_full.log, "clipboard data reply!")
+                        DaemonToAgentEvent::Paste { untrusted_data } => {
+                            trace!(agent_full.log, "clipboard data reply!");
+                            crate::clipboard::accept_incoming_from_daemon(
+                                &qubes.clipboard,
+                                untrusted_data,
+                            );
                         }
                         DaemonToAgentEvent::Keymap { new_keymap } => {
                             trace!(agent_full.log, "Keymap notification: {:?}", new_keymap);
                             let time_spent = (std::time::Instant::now() - instant).as_millis() as _;
                             let serial = SERIAL_COUNTER.next_serial();
-                            for i in 0x0..0x100 {
-                                let state = match (new_keymap.keys[i / 32] >> (i % 8)) & 0x1 {
-                                    1 => KeyState::Pressed,
-                                    0 => KeyState::Released,
-                                    _ => unreachable!(),
+                            // `new_keymap.keys` is a 256-bit (32-byte) X11
+                            // keymap vector indexed by keycode; reconcile it
+                            // against what we think is held and only emit the
+                            // transitions actually needed, rather than
+                            // replaying all 256 keys on every resync.
+                            for keycode in 8..0x100usize {
+                                let now_pressed = (new_keymap.keys[keycode >> 3] >> (keycode & 7)) & 0x1 != 0;
+                                let was_pressed = std::mem::replace(&mut qubes.pressed_keys[keycode], now_pressed);
+                                if now_pressed == was_pressed {
+                                    continue;
+                                }
+                                let state = if now_pressed {
+                                    KeyState::Pressed
+                                } else {
+                                    KeyState::Released
                                 };
                                 agent_full.keyboard.input::<(), _>(
-                                    i as _,
+                                    keycode as u32 - 8,
                                     state,
                                     serial,
                                     time_spent,
@@ -596,6 +853,14 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
                                             }
                                             Ok(true)
                                         }
+                                        #[cfg(feature = "xwayland")]
+                                        Kind::X11(x11) => {
+                                            // Route the daemon's focus change to the real X11
+                                            // window through the WM connection, the inverse of
+                                            // what xdg-activation does for in-VM clients.
+                                            x11.set_focus(has_focus);
+                                            Ok(true)
+                                        }
                                     } {
                                         surface.send_configure();
                                     }
@@ -608,6 +873,59 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
                                    "Window manager flags changed";
                                    "window" => window,
                                    "new_flags" => ?flags);
+                            if let Some(QubesBackendData { surface, .. }) =
+                                qubes.map.get(&window.try_into().unwrap())
+                            {
+                                if let Kind::Toplevel(toplevel) = surface {
+                                    let _ = toplevel.with_pending_state(|state| {
+                                        if flags.set & qubes_gui::WINDOW_FLAG_FULLSCREEN != 0 {
+                                            state.states.set(xdg_toplevel::State::Fullscreen);
+                                        }
+                                        if flags.unset & qubes_gui::WINDOW_FLAG_FULLSCREEN != 0 {
+                                            state.states.unset(xdg_toplevel::State::Fullscreen);
+                                        }
+                                        if flags.set & qubes_gui::WINDOW_FLAG_MAXIMIZE != 0 {
+                                            state.states.set(xdg_toplevel::State::Maximized);
+                                        }
+                                        if flags.unset & qubes_gui::WINDOW_FLAG_MAXIMIZE != 0 {
+                                            state.states.unset(xdg_toplevel::State::Maximized);
+                                        }
+                                        // xdg_shell has no "urgent" state of its own; the
+                                        // closest thing we can offer a client for
+                                        // demands_attention is the same Activated hint
+                                        // Focus events drive.
+                                        if flags.set & qubes_gui::WINDOW_FLAG_DEMANDS_ATTENTION != 0 {
+                                            state.states.set(xdg_toplevel::State::Activated);
+                                        }
+                                        if flags.unset & qubes_gui::WINDOW_FLAG_DEMANDS_ATTENTION != 0 {
+                                            state.states.unset(xdg_toplevel::State::Activated);
+                                        }
+                                    });
+                                    toplevel.send_configure();
+                                    if let Some(wl_surface) = toplevel.get_surface() {
+                                        if flags.set & qubes_gui::WINDOW_FLAG_MINIMIZE != 0 {
+                                            with_states(wl_surface, |data| {
+                                                data.data_map
+                                                    .get::<RefCell<SurfaceData>>()
+                                                    .unwrap()
+                                                    .borrow_mut()
+                                                    .minimized = true;
+                                            })
+                                            .ok();
+                                        }
+                                        if flags.unset & qubes_gui::WINDOW_FLAG_MINIMIZE != 0 {
+                                            with_states(wl_surface, |data| {
+                                                data.data_map
+                                                    .get::<RefCell<SurfaceData>>()
+                                                    .unwrap()
+                                                    .borrow_mut()
+                                                    .minimized = false;
+                                            })
+                                            .ok();
+                                        }
+                                    }
+                                }
+                            }
                         }
                         _ => warn!(agent_full.log, "Ignoring unknown event!"),
                     }
@@ -615,6 +933,37 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
             },
         )
         .unwrap();
+    {
+        // The Wayland display has its own pollable fd; wire it into the same
+        // event loop instead of dispatching it on a periodic timer, so we
+        // only wake up for it when a client actually has something to say.
+        let display = display.clone();
+        let log = log.clone();
+        let display_fd = display.borrow().get_poll_fd();
+        handle
+            .insert_source(
+                Generic::from_fd(
+                    display_fd,
+                    Interest {
+                        readable: true,
+                        writable: false,
+                    },
+                    calloop::Mode::Level,
+                ),
+                move |_readiness, _fd, state| {
+                    let mut display = display.borrow_mut();
+                    display.flush_clients(state);
+                    match display.dispatch(Duration::from_millis(0), state) {
+                        Ok(_) => Ok(calloop::PostAction::Continue),
+                        Err(e) => {
+                            error!(log, "Wayland display dispatch failed"; "error" => ?e);
+                            Err(e)
+                        }
+                    }
+                },
+            )
+            .expect("Failed to insert the Wayland display event source");
+    }
     {
         let log = log.clone();
         let redraw_timer =
@@ -629,57 +978,74 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
                 timer_handle.add_timeout(std::time::Duration::from_millis(time.into()), ());
                 let ref mut qubes = agent_full.backend_data.borrow_mut();
                 let mut dead_surfaces = vec![];
-                let QubesData {
-                    ref mut agent,
-                    ref mut map,
-                    ..
-                } = &mut **qubes;
+                let QubesData { ref mut map, .. } = &mut **qubes;
                 for (key, value) in map.iter_mut() {
-                    match value.surface.get_surface() {
-                        None => {
-                            info!(log, "Pushing toplevel with no surface onto dead list");
-                            dead_surfaces.push(*key);
-                            continue;
+                    // The liveness check runs every tick regardless of
+                    // `needs_frame` - an idle window whose client has gone
+                    // away still needs to be reaped. Only the frame-callback
+                    // draining below is skipped for windows a commit hasn't
+                    // marked dirty; titles move with the commit that changes
+                    // them (see surface_commit), and everything else here is
+                    // cheap enough to just re-check each tick.
+                    if value.surface.is_dead() {
+                        info!(log, "Pushing toplevel with no surface onto dead list");
+                        dead_surfaces.push(*key);
+                        continue;
+                    }
+                    // A window can lack a surface without being dead (an
+                    // X11 window before its WL_SURFACE_ID handshake
+                    // completes, see `Kind::is_dead`); there's simply
+                    // nothing to drain frame callbacks from yet.
+                    let s = match value.surface.get_surface() {
+                        None => continue,
+                        Some(s) => s,
+                    };
+                    if !value.needs_frame {
+                        continue;
+                    }
+                    let serviced = with_states(s, |states| {
+                        // A minimized window has nothing visible to
+                        // throttle against, so withhold its frame
+                        // callbacks instead of letting it burn CPU
+                        // drawing frames nobody sees.
+                        let minimized = states
+                            .data_map
+                            .get::<RefCell<SurfaceData>>()
+                            .map_or(false, |d| d.borrow().minimized);
+                        if minimized {
+                            return false;
                         }
-                        Some(s) => with_states(s, |states| {
-                            if let Some(title) = states
-                                .data_map
-                                .get::<Mutex<xdg::XdgToplevelSurfaceRoleAttributes>>()
-                                .and_then(|d| d.lock().expect("Poisoned?").title.clone())
-                            {
-                                let title: &[u8] = title.as_bytes();
-                                let mut title_buf = [0u8; 128];
-                                title_buf[..title.len().min(128)].copy_from_slice(title);
-                                agent
-                                    .send_raw(&mut title_buf, *key, qubes_gui::MSG_SET_TITLE)
-                                    .unwrap();
-                            }
-                            let attrs = &mut *states.cached_state.current::<SurfaceAttributes>();
-                            for callback in attrs.frame_callbacks.drain(..) {
-                                callback.done(time_spent);
-                            }
-                        })
-                        .expect("get_surface() only returns live resources; qed"),
+                        let attrs = &mut *states.cached_state.current::<SurfaceAttributes>();
+                        for callback in attrs.frame_callbacks.drain(..) {
+                            callback.done(time_spent);
+                        }
+                        true
+                    })
+                    .expect("get_surface() only returns live resources; qed");
+                    if serviced {
+                        value.needs_frame = false;
+                        value.last_presented = Some(std::time::Instant::now());
                     }
                 }
                 for i in dead_surfaces.iter() {
-                    trace!(log, "Destroying window"; "window" => u32::from(*i));
-                    qubes.agent.send(&qubes_gui::Destroy {}, *i).unwrap();
-                    let _: QubesBackendData = qubes
-                        .map
-                        .remove(i)
-                        .expect("these were keys in the map; qed");
-                    trace!(log, "Destruct successful"; "window" => u32::from(*i));
+                    qubes.reap_window(*i);
                 }
             })
             .expect("FIXME: handle initialization failed");
     }
 
+    // Spawns Xwayland and, once it's up, the rootless WM connection that
+    // shadows X11 toplevels/override-redirect windows onto Qubes GUI
+    // windows; see `crate::xwayland` for the details.
     #[cfg(feature = "xwayland")]
-    state.start_xwayland();
+    crate::xwayland::spawn(&handle, display.clone(), state.backend_data.clone(), log.clone());
 
     info!(log, "Initialization completed, starting the main loop.");
     let mut args = args.skip(1);
+    // Tracks the exit status of the single application we were launched to
+    // host, so the process can propagate it once the main loop below tears
+    // down; `None` if we weren't launched with a command to supervise.
+    let exit_code = Rc::new(RefCell::new(None));
     if let Some(arg) = args.next() {
         let mut v = vec![arg.clone()];
         let mut cmd = std::process::Command::new(arg);
@@ -687,18 +1053,94 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
             v.push(i.clone());
             cmd.arg(i);
         }
+
+        // We're a session leader hosting exactly one application: once it
+        // exits, there is nothing left worth keeping the agent alive for.
+        // Watch for its death via a signalfd on SIGCHLD rather than
+        // blocking in `Child::wait`, so the main loop keeps servicing the
+        // Qubes/Wayland connections while the child is still running. SIGCHLD
+        // must be blocked before the child is spawned: otherwise a child
+        // that exits before the signalfd below exists would have its
+        // SIGCHLD discarded under the default (unblocked) disposition,
+        // silently losing the only notification of that exit.
+        let mut mask = nix::sys::signal::SigSet::empty();
+        mask.add(nix::sys::signal::Signal::SIGCHLD);
+        mask.thread_block().expect("Failed to block SIGCHLD");
+
+        // `thread_block` above is inherited across fork(); left alone, the
+        // hosted application would start with SIGCHLD blocked too, which
+        // would break its own job control if it spawns and waits on
+        // children of its own. Unblock it again in the child, after the
+        // fork but before the exec, so only the agent's thread keeps it
+        // blocked.
+        unsafe {
+            cmd.pre_exec(|| {
+                let mut mask = nix::sys::signal::SigSet::empty();
+                mask.add(nix::sys::signal::Signal::SIGCHLD);
+                mask.thread_unblock().map_err(std::io::Error::from)
+            });
+        }
+
         let child = cmd.spawn().expect("Failed to execute subcommand");
         println!("Spawned child process {:?} with args {:?}", child, v);
+        let pid = nix::unistd::Pid::from_raw(child.id() as i32);
+
+        let sigfd = nix::sys::signalfd::SignalFd::with_flags(&mask, nix::sys::signalfd::SfdFlags::SFD_NONBLOCK)
+            .expect("Failed to create signalfd for SIGCHLD");
+        let sigfd_raw = sigfd.as_raw_fd();
+        let exit_code = exit_code.clone();
+        handle
+            .insert_source(
+                Generic::from_fd(
+                    sigfd_raw,
+                    Interest { readable: true, writable: false },
+                    calloop::Mode::Level,
+                ),
+                move |_readiness, _fd, agent_full| {
+                    // A signalfd read just clears the pending signal; reap
+                    // every exited child with WNOHANG since SIGCHLD can be
+                    // coalesced when several children exit in a row.
+                    let _ = sigfd.read_signal();
+                    loop {
+                        match nix::sys::wait::waitpid(
+                            nix::unistd::Pid::from_raw(-1),
+                            Some(nix::sys::wait::WaitPidFlag::WNOHANG),
+                        ) {
+                            Ok(nix::sys::wait::WaitStatus::Exited(exited_pid, status)) if exited_pid == pid => {
+                                info!(agent_full.log, "Hosted application exited"; "status" => status);
+                                *exit_code.borrow_mut() = Some(status);
+                                agent_full.running.store(false, Ordering::SeqCst);
+                                break;
+                            }
+                            Ok(nix::sys::wait::WaitStatus::Signaled(exited_pid, signal, _)) if exited_pid == pid => {
+                                warn!(agent_full.log, "Hosted application was killed"; "signal" => ?signal);
+                                *exit_code.borrow_mut() = Some(128 + signal as i32);
+                                agent_full.running.store(false, Ordering::SeqCst);
+                                break;
+                            }
+                            Ok(nix::sys::wait::WaitStatus::StillAlive) | Err(nix::errno::Errno::ECHILD) => break,
+                            Ok(_) => continue,
+                            Err(e) => {
+                                warn!(agent_full.log, "waitpid failed"; "error" => ?e);
+                                break;
+                            }
+                        }
+                    }
+                    Ok(calloop::PostAction::Continue)
+                },
+            )
+            .expect("Failed to insert the SIGCHLD event source");
     }
 
     while state.running.load(Ordering::SeqCst) {
         // Send frame events so that client start drawing their next frame
         display.borrow_mut().flush_clients(&mut state);
 
-        if event_loop
-            .dispatch(Some(Duration::from_millis(16)), &mut state)
-            .is_err()
-        {
+        // Block until some source (the Qubes agent fd, the Wayland display
+        // fd, or the redraw timer) is actually ready instead of waking up on
+        // a fixed tick: the display fd has its own source above, so nothing
+        // here needs a timeout any more.
+        if event_loop.dispatch(None, &mut state).is_err() {
             state.running.store(false, Ordering::SeqCst);
         } else {
             display.borrow_mut().flush_clients(&mut state);
@@ -707,4 +1149,8 @@ pub fn run_qubes(log: Logger, args: std::env::ArgsOs) {
         #[cfg(feature = "debug")]
         state.backend_data.fps.tick();
     }
+
+    if let Some(code) = *exit_code.borrow() {
+        std::process::exit(code);
+    }
 }
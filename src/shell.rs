@@ -10,9 +10,15 @@ use std::{
 
 use smithay::{
     backend::renderer::buffer_dimensions,
-    reexports::wayland_server::{
-        protocol::{wl_buffer, wl_shm, wl_surface::WlSurface},
-        Display,
+    reexports::{
+        wayland_protocols::{
+            unstable::xdg_decoration::v1::server::zxdg_toplevel_decoration_v1,
+            xdg_shell::server::xdg_toplevel,
+        },
+        wayland_server::{
+            protocol::{wl_buffer, wl_shm, wl_surface::WlSurface},
+            Display,
+        },
     },
     utils::{Logical, Physical, Point, Rectangle, Size},
     wayland::{
@@ -21,7 +27,13 @@ use smithay::{
             with_surface_tree_downward, BufferAssignment, Damage, SurfaceAttributes,
             TraversalAction,
         },
-        shell::xdg::{self, xdg_shell_init, ShellState as XdgShellState, XdgRequest},
+        shell::{
+            legacy::{self, wl_shell_init, ShellRequest, ShellState as WlShellState, ShellSurfaceKind},
+            xdg::{
+                self, decoration::{init_xdg_decoration_manager, XdgDecorationRequest},
+                xdg_shell_init, ShellState as XdgShellState, XdgRequest,
+            },
+        },
         shm,
     },
 };
@@ -32,6 +44,7 @@ use qubes_gui::Message as _;
 #[derive(Clone)]
 pub struct ShellHandles {
     pub xdg_state: Arc<Mutex<XdgShellState>>,
+    pub wl_state: Arc<Mutex<WlShellState>>,
 }
 
 struct QubesClient(Rc<RefCell<BTreeMap<u32, ()>>>);
@@ -95,8 +108,11 @@ pub fn init_shell(display: Rc<RefCell<Display>>, log: ::slog::Logger) -> ShellHa
                         .insert(
                             id,
                             super::qubes::QubesBackendData {
-                                surface: surface.clone(),
+                                surface: super::qubes::Kind::Toplevel(surface.clone()),
                                 has_configured: false,
+                                coordinates: Default::default(),
+                                needs_frame: false,
+                                last_presented: None,
                             }
                         )
                         .is_none());
@@ -133,16 +149,125 @@ pub fn init_shell(display: Rc<RefCell<Display>>, log: ::slog::Logger) -> ShellHa
                     )
                     .unwrap();
             }
-            XdgRequest::NewPopup {
-                surface: _,
-                positioner: _,
-            } => {
-                todo!()
+            XdgRequest::NewPopup { surface, positioner } => {
+                let raw_surface = match surface.get_surface() {
+                    Some(s) => s,
+                    // If there is no underlying surface just ignore the request
+                    None => {
+                        debug!(log, "Ignoring request to create popup with no surface");
+                        return;
+                    }
+                };
+                let anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
+                // The parent is resolved through the compositor's surface tree, not
+                // through the xdg_popup's own parent pointer: by the time NewPopup
+                // fires, xdg-shell has already reparented the popup's role.
+                let parent_window = match get_parent(raw_surface).and_then(|parent| {
+                    with_states(&parent, |data| {
+                        data.data_map
+                            .get::<RefCell<SurfaceData>>()
+                            .map(|d| d.borrow().window)
+                    })
+                    .ok()
+                    .flatten()
+                }) {
+                    Some(window) => window,
+                    None => {
+                        warn!(log, "Popup has no Qubes-tracked parent surface; dismissing");
+                        surface.send_popup_done();
+                        return;
+                    }
+                };
+                let geometry = positioner.get_geometry();
+                let _ = surface.with_pending_state(|state| {
+                    state.geometry = geometry;
+                    state.positioner = positioner;
+                });
+                let id = with_states(raw_surface, |data| {
+                    data.data_map
+                        .insert_if_missing::<RefCell<SurfaceData>, _>(|| {
+                            RefCell::new(QubesData::data(anvil_state.backend_data.clone()))
+                        });
+                    let id = data
+                        .data_map
+                        .get::<RefCell<SurfaceData>>()
+                        .unwrap()
+                        .borrow()
+                        .window;
+                    assert!(anvil_state
+                        .backend_data
+                        .borrow_mut()
+                        .map
+                        .insert(
+                            id,
+                            super::qubes::QubesBackendData {
+                                surface: super::qubes::Kind::Popup(surface.clone()),
+                                has_configured: false,
+                                coordinates: geometry.loc,
+                                needs_frame: false,
+                                last_presented: None,
+                            }
+                        )
+                        .is_none());
+                    id
+                })
+                .expect("TODO: handling dead clients");
+                let ref mut agent = anvil_state.backend_data.borrow_mut().agent;
+                let msg = qubes_gui::Create {
+                    rectangle: qubes_gui::Rectangle {
+                        top_left: qubes_gui::Coordinates {
+                            x: geometry.loc.x as u32,
+                            y: geometry.loc.y as u32,
+                        },
+                        size: qubes_gui::WindowSize {
+                            width: geometry.size.w.max(1) as _,
+                            height: geometry.size.h.max(1) as _,
+                        },
+                    },
+                    parent: Some(parent_window.into()),
+                    override_redirect: 1,
+                };
+                debug!(log, "Creating popup {} as child of {}", id, parent_window);
+                agent.client().send(&msg, id).expect("TODO: send errors");
+                let msg = qubes_gui::Configure {
+                    rectangle: msg.rectangle,
+                    override_redirect: msg.override_redirect,
+                };
+                agent.client().send(&msg, id).expect("TODO: send errors");
+                agent
+                    .client()
+                    .send(
+                        &qubes_gui::MapInfo {
+                            override_redirect: 1,
+                            transient_for: parent_window.into(),
+                        },
+                        id,
+                    )
+                    .unwrap();
+                // Popup destruction is handled by the existing dead_surfaces reaper
+                // in the redraw timer, same as toplevels.
             }
             XdgRequest::AckConfigure { surface, configure } => {
-                let configure = match configure {
-                    xdg::Configure::Toplevel(configure) => configure,
-                    xdg::Configure::Popup(_) => todo!("Popup configures"),
+                // A popup's ack-configure shares this path with toplevels,
+                // but it must keep reporting the popup's actual
+                // parent-relative position and `override_redirect: 1` - both
+                // already sent once at `NewPopup` time - or this, the
+                // guaranteed first ack, immediately snaps it back to (0,0)
+                // and un-marks it override-redirect.
+                let (top_left, size, override_redirect) = match configure {
+                    xdg::Configure::Toplevel(configure) => (
+                        qubes_gui::Coordinates::default(),
+                        configure.state.size.unwrap_or_else(|| (1, 1).into()),
+                        0,
+                    ),
+                    xdg::Configure::Popup(configure) => (
+                        qubes_gui::Coordinates {
+                            x: configure.state.geometry.loc.x.max(0) as u32,
+                            y: configure.state.geometry.loc.y.max(0) as u32,
+                        },
+                        configure.state.geometry.size,
+                        1,
+                    ),
                 };
                 with_states(&surface, |data| {
                     let mut anvil_state = _dispatch_data
@@ -157,20 +282,19 @@ pub fn init_shell(display: Rc<RefCell<Display>>, log: ::slog::Logger) -> ShellHa
                         .borrow();
                     debug!(
                         anvil_state.log,
-                        "A configure event was acknowledged!  Params: surface {:?}, configure {:?}",
+                        "A configure event was acknowledged!  Params: surface {:?}, size {:?}",
                         surface,
-                        configure
+                        size
                     );
-                    let size = configure.state.size.unwrap_or_else(|| (1, 1).into());
                     let msg = &qubes_gui::Configure {
                         rectangle: qubes_gui::Rectangle {
-                            top_left: qubes_gui::Coordinates::default(),
+                            top_left,
                             size: qubes_gui::WindowSize {
                                 width: size.w.max(1) as _,
                                 height: size.h.max(1) as _,
                             },
                         },
-                        override_redirect: 0,
+                        override_redirect,
                     };
                     anvil_state.agent.client().send(msg, state.window).unwrap()
                 })
@@ -199,29 +323,311 @@ pub fn init_shell(display: Rc<RefCell<Display>>, log: ::slog::Logger) -> ShellHa
             XdgRequest::Fullscreen {
                 surface, output: _, ..
             } => {
-                // QUBES HOOK: ask daemon to make surface fullscreen
-                // NOTE: This is only one part of the solution. We can set the
-                // location and configure size here, but the surface should be rendered fullscreen
-                // independently from its buffer size
-                let _wl_surface = if let Some(surface) = surface.get_surface() {
-                    surface
-                } else {
+                let wl_surface = match surface.get_surface() {
+                    Some(surface) => surface,
                     // If there is no underlying surface just ignore the request
-                    return;
+                    None => return,
+                };
+                let anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
+                let mut backend_data = anvil_state.backend_data.borrow_mut();
+                let screen_size = backend_data.screen_size();
+                let new_size: Size<i32, Logical> =
+                    (screen_size.width as i32, screen_size.height as i32).into();
+                let window = with_states(wl_surface, |data| {
+                    let mut surface_data = data
+                        .data_map
+                        .get::<RefCell<SurfaceData>>()
+                        .unwrap()
+                        .borrow_mut();
+                    if surface_data.pre_fullscreen_size.is_none() {
+                        surface_data.pre_fullscreen_size = surface_data.size();
+                    }
+                    surface_data.window
+                })
+                .unwrap();
+                let _ = surface.with_pending_state(|state| {
+                    state.states.set(xdg_toplevel::State::Fullscreen);
+                    state.size = Some(new_size);
+                });
+                surface.send_configure();
+                let msg = qubes_gui::Configure {
+                    rectangle: qubes_gui::Rectangle {
+                        top_left: qubes_gui::Coordinates { x: 0, y: 0 },
+                        size: screen_size,
+                    },
+                    override_redirect: 0,
+                };
+                backend_data.agent.client().send(&msg, window).unwrap();
+                backend_data
+                    .agent
+                    .client()
+                    .send(
+                        &qubes_gui::WindowFlags {
+                            set: qubes_gui::WINDOW_FLAG_FULLSCREEN,
+                            unset: 0,
+                        },
+                        window,
+                    )
+                    .unwrap();
+            }
+            XdgRequest::Maximize { surface } => {
+                let wl_surface = match surface.get_surface() {
+                    Some(surface) => surface,
+                    // If there is no underlying surface just ignore the request
+                    None => return,
+                };
+                let anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
+                let mut backend_data = anvil_state.backend_data.borrow_mut();
+                // Qubes has no notion of per-output work area, so maximize and
+                // fullscreen both resolve to the whole screen rectangle.
+                let screen_size = backend_data.screen_size();
+                let new_size: Size<i32, Logical> =
+                    (screen_size.width as i32, screen_size.height as i32).into();
+                let window = with_states(wl_surface, |data| {
+                    let mut surface_data = data
+                        .data_map
+                        .get::<RefCell<SurfaceData>>()
+                        .unwrap()
+                        .borrow_mut();
+                    if surface_data.pre_fullscreen_size.is_none() {
+                        surface_data.pre_fullscreen_size = surface_data.size();
+                    }
+                    surface_data.window
+                })
+                .unwrap();
+                let _ = surface.with_pending_state(|state| {
+                    state.states.set(xdg_toplevel::State::Maximized);
+                    state.size = Some(new_size);
+                });
+                surface.send_configure();
+                let msg = qubes_gui::Configure {
+                    rectangle: qubes_gui::Rectangle {
+                        top_left: qubes_gui::Coordinates { x: 0, y: 0 },
+                        size: screen_size,
+                    },
+                    override_redirect: 0,
                 };
-                let _msg = qubes_gui::WindowFlags { set: 1, unset: 0 };
-                todo!()
+                backend_data.agent.client().send(&msg, window).unwrap();
+                backend_data
+                    .agent
+                    .client()
+                    .send(
+                        &qubes_gui::WindowFlags {
+                            set: qubes_gui::WINDOW_FLAG_MAXIMIZE,
+                            unset: 0,
+                        },
+                        window,
+                    )
+                    .unwrap();
             }
             XdgRequest::UnMaximize { surface } => {
-                let _anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
-                let _wl_surface = if let Some(surface) = surface.get_surface() {
-                    surface
-                } else {
+                let wl_surface = match surface.get_surface() {
+                    Some(surface) => surface,
                     // If there is no underlying surface just ignore the request
+                    None => return,
+                };
+                let anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
+                let mut backend_data = anvil_state.backend_data.borrow_mut();
+                let (window, restored_size) = with_states(wl_surface, |data| {
+                    let mut surface_data = data
+                        .data_map
+                        .get::<RefCell<SurfaceData>>()
+                        .unwrap()
+                        .borrow_mut();
+                    let restored = surface_data.pre_fullscreen_size.take();
+                    (surface_data.window, restored)
+                })
+                .unwrap();
+                let restored_size = restored_size.unwrap_or_else(|| (1, 1).into());
+                let _ = surface.with_pending_state(|state| {
+                    state.states.unset(xdg_toplevel::State::Maximized);
+                    state.states.unset(xdg_toplevel::State::Fullscreen);
+                    state.size = Some(restored_size);
+                });
+                surface.send_configure();
+                let msg = qubes_gui::Configure {
+                    rectangle: qubes_gui::Rectangle {
+                        top_left: qubes_gui::Coordinates::default(),
+                        size: qubes_gui::WindowSize {
+                            width: restored_size.w.max(1) as _,
+                            height: restored_size.h.max(1) as _,
+                        },
+                    },
+                    override_redirect: 0,
+                };
+                backend_data.agent.client().send(&msg, window).unwrap();
+                backend_data
+                    .agent
+                    .client()
+                    .send(
+                        &qubes_gui::WindowFlags {
+                            set: 0,
+                            unset: qubes_gui::WINDOW_FLAG_MAXIMIZE | qubes_gui::WINDOW_FLAG_FULLSCREEN,
+                        },
+                        window,
+                    )
+                    .unwrap();
+            }
+            XdgRequest::UnFullscreen { surface } => {
+                let wl_surface = match surface.get_surface() {
+                    Some(surface) => surface,
+                    // If there is no underlying surface just ignore the request
+                    None => return,
+                };
+                let anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
+                let mut backend_data = anvil_state.backend_data.borrow_mut();
+                let (window, restored_size) = with_states(wl_surface, |data| {
+                    let mut surface_data = data
+                        .data_map
+                        .get::<RefCell<SurfaceData>>()
+                        .unwrap()
+                        .borrow_mut();
+                    let restored = surface_data.pre_fullscreen_size.take();
+                    (surface_data.window, restored)
+                })
+                .unwrap();
+                let restored_size = restored_size.unwrap_or_else(|| (1, 1).into());
+                let _ = surface.with_pending_state(|state| {
+                    state.states.unset(xdg_toplevel::State::Fullscreen);
+                    state.states.unset(xdg_toplevel::State::Maximized);
+                    state.size = Some(restored_size);
+                });
+                surface.send_configure();
+                let msg = qubes_gui::Configure {
+                    rectangle: qubes_gui::Rectangle {
+                        top_left: qubes_gui::Coordinates::default(),
+                        size: qubes_gui::WindowSize {
+                            width: restored_size.w.max(1) as _,
+                            height: restored_size.h.max(1) as _,
+                        },
+                    },
+                    override_redirect: 0,
+                };
+                backend_data.agent.client().send(&msg, window).unwrap();
+                backend_data
+                    .agent
+                    .client()
+                    .send(
+                        &qubes_gui::WindowFlags {
+                            set: 0,
+                            unset: qubes_gui::WINDOW_FLAG_MAXIMIZE | qubes_gui::WINDOW_FLAG_FULLSCREEN,
+                        },
+                        window,
+                    )
+                    .unwrap();
+            }
+            XdgRequest::Move {
+                surface,
+                seat: _,
+                serial,
+            } => {
+                let wl_surface = match surface.get_surface() {
+                    Some(surface) => surface,
+                    None => return,
+                };
+                let anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
+                if !anvil_state.pointer.has_grab(serial) {
+                    return;
+                }
+                let start_data = anvil_state.pointer.grab_start_data().unwrap();
+                // Only start the grab if the pointer is currently pressed on
+                // the surface asking for the move, same check anvil does.
+                let focus_matches = start_data
+                    .focus
+                    .as_ref()
+                    .map(|(focus, _)| focus == wl_surface)
+                    .unwrap_or(false);
+                if !focus_matches {
+                    return;
+                }
+                let (window, window_size, location) = with_states(wl_surface, |data| {
+                    let surface_data = data
+                        .data_map
+                        .get::<RefCell<SurfaceData>>()
+                        .unwrap()
+                        .borrow();
+                    (
+                        surface_data.window,
+                        surface_data.size(),
+                        surface_data.coordinates,
+                    )
+                })
+                .unwrap();
+                let window_size = match window_size {
+                    Some(size) => qubes_gui::WindowSize {
+                        width: size.w.max(1) as _,
+                        height: size.h.max(1) as _,
+                    },
+                    None => return,
+                };
+                let grab = crate::grab::MoveSurfaceGrab {
+                    start_data,
+                    window,
+                    backend_data: anvil_state.backend_data.clone(),
+                    initial_window_location: (location.x as i32, location.y as i32).into(),
+                    window_size,
+                };
+                anvil_state.pointer.set_grab(grab, serial);
+            }
+            XdgRequest::Resize {
+                surface,
+                seat: _,
+                serial,
+                edges,
+            } => {
+                let wl_surface = match surface.get_surface() {
+                    Some(surface) => surface,
+                    None => return,
+                };
+                let anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
+                if !anvil_state.pointer.has_grab(serial) {
                     return;
+                }
+                let start_data = anvil_state.pointer.grab_start_data().unwrap();
+                let focus_matches = start_data
+                    .focus
+                    .as_ref()
+                    .map(|(focus, _)| focus == wl_surface)
+                    .unwrap_or(false);
+                if !focus_matches {
+                    return;
+                }
+                let (window, initial_window_size, initial_window_location) =
+                    with_states(wl_surface, |data| {
+                        let surface_data = data
+                            .data_map
+                            .get::<RefCell<SurfaceData>>()
+                            .unwrap()
+                            .borrow();
+                        (
+                            surface_data.window,
+                            surface_data.size(),
+                            surface_data.coordinates,
+                        )
+                    })
+                    .unwrap();
+                let initial_window_size = match initial_window_size {
+                    Some(size) => size,
+                    None => return,
                 };
-                let _msg = qubes_gui::WindowFlags { set: 0, unset: 1 };
-                todo!()
+                let _ = surface.with_pending_state(|state| {
+                    state.states.set(xdg_toplevel::State::Resizing);
+                });
+                surface.send_configure();
+                let grab = crate::grab::ResizeSurfaceGrab {
+                    start_data,
+                    toplevel: surface,
+                    window,
+                    backend_data: anvil_state.backend_data.clone(),
+                    edges,
+                    initial_window_size,
+                    initial_window_location: (
+                        initial_window_location.x as i32,
+                        initial_window_location.y as i32,
+                    )
+                        .into(),
+                };
+                anvil_state.pointer.set_grab(grab, serial);
             }
             XdgRequest::NewClient { client } => {
                 let anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
@@ -239,18 +645,255 @@ pub fn init_shell(display: Rc<RefCell<Display>>, log: ::slog::Logger) -> ShellHa
         log_,
     );
 
+    let log_ = log.clone();
+    let (wl_shell_state, _) = wl_shell_init(
+        &mut *display.borrow_mut(),
+        move |shell_event, mut _dispatch_data| match shell_event {
+            ShellRequest::NewClient { client } => {
+                let anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
+                info!(anvil_state.log, "New wl_shell client connected!");
+                client
+                    .with_data(|data| {
+                        data.insert_if_missing(|| {
+                            QubesClient(Rc::new(RefCell::new(BTreeMap::new())))
+                        })
+                    })
+                    .expect("New clients are not dead");
+            }
+            ShellRequest::NewShellSurface { surface: _ } => {
+                // No Qubes window yet: wl_shell doesn't tell us the kind
+                // (toplevel/transient/popup) until SetKind arrives.
+            }
+            ShellRequest::SetKind { surface, kind } => {
+                let raw_surface = match surface.get_surface() {
+                    Some(s) => s,
+                    // If there is no underlying surface just ignore the request
+                    None => {
+                        debug!(log, "Ignoring wl_shell surface with no surface");
+                        return;
+                    }
+                };
+                let anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
+                let (id, size) = with_states(raw_surface, |data| {
+                    data.data_map
+                        .insert_if_missing::<RefCell<SurfaceData>, _>(|| {
+                            RefCell::new(QubesData::data(anvil_state.backend_data.clone()))
+                        });
+                    let surface_data = data.data_map.get::<RefCell<SurfaceData>>().unwrap();
+                    let id = surface_data.borrow().window;
+                    let size = surface_data.borrow().size().unwrap_or_else(|| (1, 1).into());
+                    (id, size)
+                })
+                .expect("TODO: handling dead clients");
+                let (parent, location, override_redirect): (Option<NonZeroU32>, Point<i32, Logical>, u32) =
+                    match &kind {
+                        ShellSurfaceKind::Toplevel
+                        | ShellSurfaceKind::Maximized { .. }
+                        | ShellSurfaceKind::Fullscreen { .. } => (None, (0, 0).into(), 0),
+                        ShellSurfaceKind::Transient { parent, location, .. } => (
+                            with_states(parent, |data| {
+                                data.data_map
+                                    .get::<RefCell<SurfaceData>>()
+                                    .map(|d| d.borrow().window)
+                            })
+                            .ok()
+                            .flatten(),
+                            *location,
+                            1,
+                        ),
+                        ShellSurfaceKind::Popup { parent, location, .. } => (
+                            with_states(parent, |data| {
+                                data.data_map
+                                    .get::<RefCell<SurfaceData>>()
+                                    .map(|d| d.borrow().window)
+                            })
+                            .ok()
+                            .flatten(),
+                            *location,
+                            1,
+                        ),
+                    };
+                assert!(anvil_state
+                    .backend_data
+                    .borrow_mut()
+                    .map
+                    .insert(
+                        id,
+                        super::qubes::QubesBackendData {
+                            surface: super::qubes::Kind::Legacy(surface.clone()),
+                            has_configured: false,
+                            coordinates: location,
+                            needs_frame: false,
+                            last_presented: None,
+                        }
+                    )
+                    .is_none());
+                let ref mut agent = anvil_state.backend_data.borrow_mut().agent;
+                let msg = qubes_gui::Create {
+                    rectangle: qubes_gui::Rectangle {
+                        top_left: qubes_gui::Coordinates {
+                            x: location.x.max(0) as u32,
+                            y: location.y.max(0) as u32,
+                        },
+                        size: qubes_gui::WindowSize {
+                            width: size.w.max(1) as _,
+                            height: size.h.max(1) as _,
+                        },
+                    },
+                    parent: parent.map(Into::into),
+                    override_redirect,
+                };
+                debug!(log, "Creating wl_shell window {}", id);
+                agent.client().send(&msg, id).expect("TODO: send errors");
+                let msg = qubes_gui::Configure {
+                    rectangle: msg.rectangle,
+                    override_redirect: msg.override_redirect,
+                };
+                agent.client().send(&msg, id).expect("TODO: send errors");
+                agent
+                    .client()
+                    .send(
+                        &qubes_gui::MapInfo {
+                            override_redirect,
+                            transient_for: parent.map(u32::from).unwrap_or(0),
+                        },
+                        id,
+                    )
+                    .unwrap();
+            }
+            ShellRequest::SetTitle { surface, title } => {
+                let anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
+                let window = surface.get_surface().and_then(|raw_surface| {
+                    with_states(raw_surface, |data| {
+                        data.data_map
+                            .get::<RefCell<SurfaceData>>()
+                            .map(|d| d.borrow().window)
+                    })
+                    .ok()
+                    .flatten()
+                });
+                if let Some(window) = window {
+                    let bytes = title.as_bytes();
+                    let mut buf = [0u8; 128];
+                    buf[..bytes.len().min(128)].copy_from_slice(&bytes[..bytes.len().min(128)]);
+                    anvil_state
+                        .backend_data
+                        .borrow_mut()
+                        .agent
+                        .client()
+                        .send_raw(&mut buf, window, qubes_gui::MSG_SET_TITLE)
+                        .unwrap();
+                }
+            }
+            ShellRequest::SetClass { surface, class } => {
+                let anvil_state = _dispatch_data.get::<AnvilState>().unwrap();
+                let window = surface.get_surface().and_then(|raw_surface| {
+                    with_states(raw_surface, |data| {
+                        data.data_map
+                            .get::<RefCell<SurfaceData>>()
+                            .map(|d| d.borrow().window)
+                    })
+                    .ok()
+                    .flatten()
+                });
+                if let Some(window) = window {
+                    debug!(log, "wl_shell window {} set class {:?}", window, class);
+                    let bytes = class.as_bytes();
+                    let mut buf = [0u8; 128];
+                    buf[..bytes.len().min(128)].copy_from_slice(&bytes[..bytes.len().min(128)]);
+                    anvil_state
+                        .backend_data
+                        .borrow_mut()
+                        .agent
+                        .client()
+                        .send_raw(&mut buf, window, qubes_gui::MSG_WMCLASS)
+                        .unwrap();
+                }
+            }
+            other => println!("Got an unhandled wl_shell event: {:?}", other),
+        },
+        log_,
+    );
+
+    // The Qubes GUI daemon always draws the window border and title bar
+    // itself, so clients must never paint their own: force ServerSide
+    // unconditionally rather than honoring whatever mode they request.
+    init_xdg_decoration_manager(
+        &mut *display.borrow_mut(),
+        move |request, _dispatch_data| match request {
+            XdgDecorationRequest::NewToplevelDecoration { toplevel }
+            | XdgDecorationRequest::SetMode { toplevel, .. }
+            | XdgDecorationRequest::UnsetMode { toplevel } => {
+                let _ = toplevel.with_pending_state(|state| {
+                    state.decoration_mode = Some(zxdg_toplevel_decoration_v1::Mode::ServerSide);
+                });
+                toplevel.send_configure();
+            }
+        },
+        log.clone(),
+    );
+
     ShellHandles {
         xdg_state: xdg_shell_state,
+        wl_state: wl_shell_state,
+    }
+}
+
+fn rects_touch<Kind>(a: &Rectangle<i32, Kind>, b: &Rectangle<i32, Kind>) -> bool {
+    let a_right = a.loc.x + a.size.w;
+    let a_bottom = a.loc.y + a.size.h;
+    let b_right = b.loc.x + b.size.w;
+    let b_bottom = b.loc.y + b.size.h;
+    a.loc.x <= b_right && b.loc.x <= a_right && a.loc.y <= b_bottom && b.loc.y <= a_bottom
+}
+
+fn merge_rects<Kind>(a: &Rectangle<i32, Kind>, b: &Rectangle<i32, Kind>) -> Rectangle<i32, Kind> {
+    let x = a.loc.x.min(b.loc.x);
+    let y = a.loc.y.min(b.loc.y);
+    let right = (a.loc.x + a.size.w).max(b.loc.x + b.size.w);
+    let bottom = (a.loc.y + a.size.h).max(b.loc.y + b.size.h);
+    Rectangle {
+        loc: (x, y).into(),
+        size: (right - x, bottom - y).into(),
     }
 }
 
+/// Merge overlapping/adjacent damage rectangles so that a commit which
+/// dirties several small areas produces a bounded number of `ShmImage`
+/// messages, rather than one per damage region (and, previously, one per
+/// scanline within each region).
+fn coalesce_damage<Kind: Clone>(rects: Vec<Rectangle<i32, Kind>>) -> Vec<Rectangle<i32, Kind>> {
+    let mut merged: Vec<Rectangle<i32, Kind>> = Vec::with_capacity(rects.len());
+    'rect: for rect in rects {
+        for existing in merged.iter_mut() {
+            if rects_touch(existing, &rect) {
+                *existing = merge_rects(existing, &rect);
+                continue 'rect;
+            }
+        }
+        merged.push(rect);
+    }
+    merged
+}
+
 pub struct SurfaceData {
     pub buffer: Option<(wl_buffer::WlBuffer, qubes_gui_client::agent::Buffer)>,
     pub geometry: Option<Rectangle<i32, Logical>>,
     pub buffer_dimensions: Option<Size<i32, Physical>>,
     pub buffer_scale: i32,
     pub window: std::num::NonZeroU32,
-    pub qubes: Rc<RefCell<QubesData>>,
+    pub buffer_swapped: bool,
+    pub coordinates: qubes_gui::Coordinates,
+    /// The toplevel's size before it was fullscreened or maximized, so it can
+    /// be restored when the client (or the daemon) asks to leave that state.
+    pub pre_fullscreen_size: Option<Size<i32, Logical>>,
+    /// Whether the dom0 window manager has minimized this toplevel. xdg_shell
+    /// has no "minimized" configure state for us to report back to the
+    /// client, so instead we throttle it by withholding frame callbacks.
+    pub minimized: bool,
+    /// The last window title we actually sent to the daemon, so commits that
+    /// don't change the title don't retransmit `MSG_SET_TITLE`.
+    pub last_title: Option<Box<[u8]>>,
 }
 
 impl SurfaceData {
@@ -349,17 +992,19 @@ impl SurfaceData {
                                 size: (width, height).into(),
                             }));
                         }
-                        for i in attrs.damage.drain(..) {
-                            let (untrusted_loc, untrusted_size) = match i {
-                                Damage::Surface(r) => {
-                                    let r = r.to_buffer(self.buffer_scale);
-                                    (r.loc, r.size)
-                                }
-                                Damage::Buffer(Rectangle {
-                                    loc: untrusted_loc,
-                                    size: untrusted_size,
-                                }) => (untrusted_loc, untrusted_size),
-                            };
+                        let damage_rects: Vec<Rectangle<i32, smithay::utils::Buffer>> = attrs
+                            .damage
+                            .drain(..)
+                            .map(|d| match d {
+                                Damage::Surface(r) => r.to_buffer(self.buffer_scale),
+                                Damage::Buffer(r) => r,
+                            })
+                            .collect();
+                        for Rectangle {
+                            loc: untrusted_loc,
+                            size: untrusted_size,
+                        } in coalesce_damage(damage_rects)
+                        {
                             // SANITIZE START
                             if untrusted_size.w <= 0
                                 || untrusted_size.h <= 0
@@ -375,7 +1020,7 @@ impl SurfaceData {
                                 return;
                             }
                             let mut w = untrusted_size.w.min(width - untrusted_loc.x);
-                            let mut h = untrusted_size.w.min(height - untrusted_loc.y);
+                            let mut h = untrusted_size.h.min(height - untrusted_loc.y);
                             let (mut x, mut y) = (untrusted_loc.x, untrusted_loc.y);
                             // MEGA-HACK FOR QUBES
                             //
@@ -415,26 +1060,37 @@ impl SurfaceData {
                                     &subslice[start_offset..bytes_to_write + start_offset],
                                     offset_in_dest_buffer,
                                 );
-                                let output_message = qubes_gui::ShmImage {
-                                    rectangle: qubes_gui::Rectangle {
-                                        top_left: qubes_gui::Coordinates {
-                                            x: x as u32,
-                                            y: y as u32,
-                                        },
-                                        size: qubes_gui::WindowSize {
-                                            width: w as u32,
-                                            height: w as u32,
-                                        },
-                                    },
-                                };
-                                client
-                                    .send(&output_message, self.window.into())
-                                    .expect("TODO");
                             }
+                            // One ShmImage per (coalesced) damage rectangle,
+                            // not one per scanline.
+                            let output_message = qubes_gui::ShmImage {
+                                rectangle: qubes_gui::Rectangle {
+                                    top_left: qubes_gui::Coordinates {
+                                        x: x as u32,
+                                        y: y as u32,
+                                    },
+                                    size: qubes_gui::WindowSize {
+                                        width: w as u32,
+                                        height: h as u32,
+                                    },
+                                },
+                            };
+                            client
+                                .send(&output_message, self.window.into())
+                                .expect("TODO");
                         }
                     },
                 ) {
-                    Ok(()) => attrs.damage.clear(),
+                    Ok(()) => {
+                        attrs.damage.clear();
+                        // We just pushed new content to the daemon; the next
+                        // redraw tick should fire this window's pending frame
+                        // callbacks so the client can start drawing its next
+                        // frame; windows without new damage are skipped.
+                        if let Some(entry) = data.map.get_mut(&self.window) {
+                            entry.needs_frame = true;
+                        }
+                    }
                     Err(shm::BufferAccessError::NotManaged) => panic!("strange shm buffer"),
                     Err(shm::BufferAccessError::BadMap) => return,
                 }
@@ -505,23 +1161,53 @@ fn surface_commit(surface: &WlSurface, backend_data: &Rc<RefCell<QubesData>>) {
                     .into();
                 TraversalAction::DoChildren(res)
             },
-            |_surface: &WlSurface,
+            |surface: &WlSurface,
              states: &compositor::SurfaceData,
              &parent: &Option<NonZeroU32>| {
                 let geometry = states
                     .cached_state
                     .current::<xdg::SurfaceCachedState>()
                     .geometry;
-                states
+                let surface_data = states.data_map.get::<RefCell<SurfaceData>>().unwrap();
+                let had_buffer = surface_data.borrow().buffer.is_some();
+                surface_data.borrow_mut().update_buffer(
+                    &mut *states.cached_state.current::<SurfaceAttributes>(),
+                    &mut *backend_data.borrow_mut(),
+                    geometry,
+                );
+                let has_buffer = surface_data.borrow().buffer.is_some();
+                // Tell the client which output its newly-visible (or
+                // newly-hidden) content is on, so it can pick the right scale.
+                let output = backend_data.borrow().output.output.clone();
+                if has_buffer && !had_buffer {
+                    output.enter(surface);
+                } else if !has_buffer && had_buffer {
+                    output.leave(surface);
+                }
+                // xdg_toplevel has no dedicated "title changed" request (unlike
+                // legacy wl_shell's ShellRequest::SetTitle above), so the only
+                // place to notice a title change is here, on commit. Only send
+                // MSG_SET_TITLE when it actually changed, instead of every
+                // redraw tick.
+                if let Some(title) = states
                     .data_map
-                    .get::<RefCell<SurfaceData>>()
-                    .unwrap()
-                    .borrow_mut()
-                    .update_buffer(
-                        &mut *states.cached_state.current::<SurfaceAttributes>(),
-                        &mut *backend_data.borrow_mut(),
-                        geometry,
-                    );
+                    .get::<Mutex<xdg::XdgToplevelSurfaceRoleAttributes>>()
+                    .and_then(|d| d.lock().expect("Poisoned?").title.clone())
+                {
+                    let bytes = title.as_bytes();
+                    let mut surface_data = surface_data.borrow_mut();
+                    if surface_data.last_title.as_deref() != Some(bytes) {
+                        let window = surface_data.window;
+                        let mut title_buf = [0u8; 128];
+                        title_buf[..bytes.len().min(128)].copy_from_slice(&bytes[..bytes.len().min(128)]);
+                        backend_data
+                            .borrow_mut()
+                            .agent
+                            .send_raw(&mut title_buf, window, qubes_gui::MSG_SET_TITLE)
+                            .unwrap();
+                        surface_data.last_title = Some(bytes.into());
+                    }
+                }
             },
             |_surface: &WlSurface, _surface_data, _| true,
         );
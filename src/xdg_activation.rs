@@ -0,0 +1,172 @@
+//! `xdg-activation-v1`: lets a Wayland client inside the VM ask for one of
+//! its own surfaces (typically a freshly-mapped dialog) to be raised and
+//! focused, the inverse of the `Focus` event the daemon already sends us
+//! (handled in `qubes.rs`). We mint a token on request, and once a client
+//! sends an `activate` request carrying that token for one of its surfaces
+//! we both raise the request to the Qubes daemon (as a
+//! `WINDOW_FLAG_DEMANDS_ATTENTION` window flag) and flip the local Activated
+//! state exactly like an incoming `Focus` event would.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use smithay::reexports::{
+    wayland_protocols::{staging::xdg_activation::v1::server::{xdg_activation_token_v1, xdg_activation_v1},
+                         xdg_shell::server::xdg_toplevel},
+    wayland_server::{protocol::wl_surface::WlSurface, Client, Display, Filter},
+};
+use smithay::wayland::{compositor::with_states, SERIAL_COUNTER};
+
+use crate::{
+    qubes::{Kind, QubesBackendData},
+    shell::SurfaceData,
+    state::AnvilState,
+};
+
+/// xdg-activation-v1 requires tokens to be both client-scoped and
+/// time-bounded so a compromised or merely slow client can't steal focus for
+/// a surface long after the token was minted. Five seconds is generous for
+/// "spawn a dialog and activate it immediately" while still closing that
+/// window for anything else.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(5);
+
+struct PendingToken {
+    /// The client that asked for this token, so `activate` can refuse a
+    /// token handed to a different client than the one that requested it.
+    requester: Client,
+    issued_at: Instant,
+}
+
+#[derive(Default)]
+pub struct XdgActivationState {
+    tokens: HashMap<String, PendingToken>,
+}
+
+impl XdgActivationState {
+    fn issue_token(&mut self, requester: Client) -> String {
+        // Drawn from the kernel CSPRNG so a client can't guess another
+        // client's token within its short validity window.
+        let token = format!("{:016x}{:016x}", rand_u64(), rand_u64());
+        self.tokens.insert(
+            token.clone(),
+            PendingToken {
+                requester,
+                issued_at: Instant::now(),
+            },
+        );
+        token
+    }
+
+    /// Consume a token, returning whether it was valid for `client` - present,
+    /// unexpired, and originally issued to the same client requesting
+    /// activation.
+    fn validate(&mut self, token: &str, client: &Client) -> bool {
+        match self.tokens.remove(token) {
+            Some(pending) => pending.issued_at.elapsed() < TOKEN_LIFETIME && &pending.requester == client,
+            None => false,
+        }
+    }
+}
+
+fn rand_u64() -> u64 {
+    // getrandom(2) is one syscall away since nix is already a dependency;
+    // no need to pull in a full RNG crate just for an unguessable token.
+    let mut bytes = [0u8; 8];
+    nix::sys::random::getrandom(&mut bytes).expect("getrandom() failed");
+    u64::from_ne_bytes(bytes)
+}
+
+pub fn init_xdg_activation(display: &mut Display, log: ::slog::Logger) -> Rc<RefCell<XdgActivationState>> {
+    let state = Rc::new(RefCell::new(XdgActivationState::default()));
+    display.create_global::<xdg_activation_v1::XdgActivationV1, _>(
+        1,
+        Filter::new({
+            let state = state.clone();
+            let log = log.clone();
+            move |(main, _version), _filter, _ddata| {
+                let state = state.clone();
+                let log = log.clone();
+                main.quick_assign(move |main, request, mut ddata| match request {
+                    xdg_activation_v1::Request::GetActivationToken { id } => {
+                        // Hints like app_id/serial/surface aren't needed for
+                        // the validation we do here - a same-client token
+                        // minted inside its lifetime is enough - so only
+                        // `commit` is handled.
+                        id.quick_assign({
+                            let state = state.clone();
+                            move |token_resource, request, _ddata| {
+                                if let xdg_activation_token_v1::Request::Commit = request {
+                                    let requester = token_resource
+                                        .as_ref()
+                                        .client()
+                                        .expect("dead client committing a token?");
+                                    let token = state.borrow_mut().issue_token(requester);
+                                    token_resource.send_event(xdg_activation_token_v1::Event::Done { token });
+                                }
+                            }
+                        });
+                    }
+                    xdg_activation_v1::Request::Activate { token, surface } => {
+                        let client = main.as_ref().client().expect("dead client activating a surface?");
+                        let valid = state.borrow_mut().validate(&token, &client);
+                        if !valid {
+                            debug!(log, "Refusing xdg-activation request with invalid/expired token");
+                            return;
+                        }
+                        activate_surface(&surface, &mut ddata);
+                    }
+                    xdg_activation_v1::Request::Destroy => {}
+                    _ => {}
+                });
+            }
+        }),
+        log,
+    );
+    state
+}
+
+/// Mirror what the `Focus` event handler in `qubes.rs` does for an incoming
+/// daemon-driven focus change, but in the other direction: set the Activated
+/// state locally, raise the request to the daemon (so dom0's stacking/focus
+/// policy agrees), then move keyboard focus to the surface.
+fn activate_surface(surface: &WlSurface, ddata: &mut smithay::reexports::wayland_server::DispatchData) {
+    let anvil_state = match ddata.get::<AnvilState>() {
+        Some(state) => state,
+        None => return,
+    };
+    let window = match with_states(surface, |data| {
+        data.data_map
+            .get::<RefCell<SurfaceData>>()
+            .map(|d| d.borrow().window)
+    })
+    .ok()
+    .flatten()
+    {
+        Some(window) => window,
+        None => return,
+    };
+    let mut backend_data = anvil_state.backend_data.borrow_mut();
+    if let Some(QubesBackendData { surface: Kind::Toplevel(toplevel), .. }) = backend_data.map.get(&window) {
+        let _ = toplevel.with_pending_state(|state| {
+            state.states.set(xdg_toplevel::State::Activated);
+        });
+        toplevel.send_configure();
+    }
+    // Sending WINDOW_FLAG_DEMANDS_ATTENTION asks dom0 to raise and focus the
+    // window, the actual authority over stacking/focus.
+    let _ = backend_data.agent.client().send(
+        &qubes_gui::WindowFlags {
+            set: qubes_gui::WINDOW_FLAG_DEMANDS_ATTENTION,
+            unset: 0,
+        },
+        window,
+    );
+    drop(backend_data);
+    anvil_state
+        .keyboard
+        .set_focus(Some(surface.clone()), SERIAL_COUNTER.next_serial());
+}
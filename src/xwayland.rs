@@ -0,0 +1,548 @@
+//! Rootless XWayland window management.
+//!
+//! Smithay's `XWayland` helper only gets the server running and hands us the
+//! socket once it's ready; it does not make us a window manager. X11
+//! toplevels (and override-redirect windows, which never go through a real
+//! WM at all) need someone to watch `MapRequest`/`ConfigureRequest`/property
+//! changes and turn them into Qubes GUI windows, the same way `shell.rs`
+//! turns `xdg_shell`/`wl_shell` requests into them. This module is that
+//! someone: a minimal ICCCM/EWMH WM connection, analogous to what other
+//! rootless compositors (e.g. StardustXR) run alongside their Xwayland
+//! instance.
+//!
+//! Each X11 window we're shadowing gets exactly one `QubesBackendData` entry
+//! in `QubesData::map`, tagged `Kind::X11`, so the rest of the agent (input
+//! routing, the redraw timer, the daemon's Configure/Focus/Close events)
+//! doesn't need to know or care that it isn't a native Wayland surface.
+//!
+//! `X11Surface` is paired with the `wl_surface` the same client creates for
+//! it via the traditional XWayland `WL_SURFACE_ID` client message (the
+//! handshake rootless WMs used before `xwayland-shell-v1` existed, and which
+//! this older Xwayland/smithay pairing still speaks): Xwayland sends it to
+//! the X11 window carrying the Wayland object id of the surface the same
+//! client created, as soon as that surface exists. The two events - the
+//! X11-side `MapRequest`/override-redirect `MapNotify` and the Wayland-side
+//! `WL_SURFACE_ID` - can arrive in either order, so `Wm` keeps a small
+//! pending table in each direction until both halves are known.
+use std::{
+    cell::{Cell, OnceCell, RefCell},
+    collections::HashMap,
+    num::NonZeroU32,
+    os::unix::io::AsRawFd,
+    rc::Rc,
+};
+
+use smithay::reexports::{
+    calloop::{generic::Generic, Interest, LoopHandle, Mode as CalloopMode, PostAction},
+    wayland_server::{protocol::wl_surface::WlSurface, Client, Display},
+};
+use smithay::xwayland::{XWayland, XWaylandEvent};
+
+use x11rb::{
+    connection::Connection as _,
+    protocol::{
+        xproto::{
+            ChangeWindowAttributesAux, ConfigureWindowAux, ConnectionExt as _, EventMask, InputFocus,
+            Window as X11Window,
+        },
+        Event,
+    },
+    rust_connection::RustConnection,
+};
+
+use crate::{
+    qubes::{Kind, QubesBackendData, QubesData},
+    shell::SurfaceData,
+    state::AnvilState,
+};
+
+x11rb::atom_manager! {
+    Atoms: AtomsCookie {
+        WM_PROTOCOLS,
+        WM_DELETE_WINDOW,
+        WM_TRANSIENT_FOR,
+        WL_SURFACE_ID,
+        _NET_WM_NAME,
+        UTF8_STRING,
+    }
+}
+
+/// Per-window state for a rootless XWayland surface, stashed inside
+/// `Kind::X11` so `qubes.rs` can drive it through the same `send_configure`/
+/// `send_close`/`get_surface` paths as every other `Kind`.
+pub struct X11Surface {
+    window: X11Window,
+    wm: Rc<RefCell<Wm>>,
+    /// The size the Qubes daemon last told us this window should be, so
+    /// `note_configure` can tell whether a `Configure` actually changed
+    /// anything - mirrors what `ToplevelSurface`/`PopupSurface` track
+    /// internally for the other `Kind`s.
+    pending_size: Cell<Option<(i32, i32)>>,
+    /// Set exactly once, by the `WL_SURFACE_ID` handshake in `pair_wl_surface`.
+    /// A `OnceCell` (rather than `RefCell`) is enough since a window's
+    /// `wl_surface` never changes once paired, and lets `get_surface` hand
+    /// back a plain `&WlSurface` the way every other `Kind` does.
+    wl_surface: OnceCell<WlSurface>,
+}
+
+impl X11Surface {
+    /// The paired `wl_surface`, once the `WL_SURFACE_ID` handshake has
+    /// completed; `None` until then. (For the other kinds, `None` means the
+    /// surface is gone; see `Kind::is_dead`.)
+    pub fn get_surface(&self) -> Option<&WlSurface> {
+        self.wl_surface.get()
+    }
+
+    pub fn send_configure(&self) {
+        if let Some((width, height)) = self.pending_size.get() {
+            self.wm.borrow().configure_window(self.window, width, height);
+        }
+    }
+
+    pub fn send_close(&self) {
+        self.wm.borrow().close_window(self.window);
+    }
+
+    pub fn set_focus(&self, has_focus: bool) {
+        self.wm.borrow().set_input_focus(self.window, has_focus);
+    }
+
+    /// Record the size the daemon wants this window to have; returns
+    /// whether it's unchanged from what we last recorded, matching the
+    /// `do_send` check `process_client_configure` does for the other kinds.
+    pub fn note_configure(&self, width: i32, height: i32) -> bool {
+        let new_size = Some((width, height));
+        let unchanged = self.pending_size.get() == new_size;
+        self.pending_size.set(new_size);
+        unchanged
+    }
+}
+
+/// The WM side of the XWayland connection: enough ICCCM/EWMH to notice new,
+/// reconfigured, and dying X11 windows. This is deliberately not a "real"
+/// window manager - it has no stacking or decoration policy of its own,
+/// since dom0's GUI daemon already owns all of that for us.
+struct Wm {
+    conn: RustConnection,
+    atoms: Atoms,
+    root: X11Window,
+    /// The Xwayland server's own Wayland client connection - the one whose
+    /// surfaces `WL_SURFACE_ID` object ids are resolved against.
+    client: Client,
+    by_x11_window: HashMap<X11Window, NonZeroU32>,
+    /// `WL_SURFACE_ID` arrived for a window we haven't created a Qubes
+    /// window for yet (it can race `MapRequest`/override-redirect
+    /// `MapNotify`); stashed here until `create_qubes_window` catches up.
+    pending_wl_surfaces: HashMap<X11Window, WlSurface>,
+    log: ::slog::Logger,
+}
+
+impl Wm {
+    fn configure_window(&self, window: X11Window, width: i32, height: i32) {
+        let aux = ConfigureWindowAux::new()
+            .width(width.max(1) as u32)
+            .height(height.max(1) as u32);
+        if let Err(e) = self.conn.configure_window(window, &aux) {
+            warn!(self.log, "Failed to configure X11 window"; "window" => window, "error" => ?e);
+        }
+        let _ = self.conn.flush();
+    }
+
+    fn close_window(&self, window: X11Window) {
+        // ICCCM 4.2.8: ask nicely via WM_DELETE_WINDOW first; clients that
+        // never adopted WM_PROTOCOLS just get killed, the same fallback any
+        // "force quit" window manager action takes.
+        let data = x11rb::protocol::xproto::ClientMessageData::from([
+            self.atoms.WM_DELETE_WINDOW,
+            x11rb::CURRENT_TIME,
+            0,
+            0,
+            0,
+        ]);
+        let event = x11rb::protocol::xproto::ClientMessageEvent::new(32, window, self.atoms.WM_PROTOCOLS, data);
+        if self.conn.send_event(false, window, EventMask::NO_EVENT, event).is_err() {
+            let _ = self.conn.kill_client(window);
+        }
+        let _ = self.conn.flush();
+    }
+
+    fn set_input_focus(&self, window: X11Window, has_focus: bool) {
+        let target = if has_focus { window } else { self.root };
+        let _ = self.conn.set_input_focus(InputFocus::PARENT, target, x11rb::CURRENT_TIME);
+        let _ = self.conn.flush();
+    }
+
+    fn read_title(&self, window: X11Window) -> Option<String> {
+        let reply = self
+            .conn
+            .get_property(false, window, self.atoms._NET_WM_NAME, self.atoms.UTF8_STRING, 0, 1024)
+            .ok()?
+            .reply()
+            .ok()?;
+        String::from_utf8(reply.value).ok()
+    }
+
+    fn transient_for(&self, window: X11Window) -> Option<X11Window> {
+        self.conn
+            .get_property(
+                false,
+                window,
+                self.atoms.WM_TRANSIENT_FOR,
+                x11rb::protocol::xproto::AtomEnum::WINDOW,
+                0,
+                1,
+            )
+            .ok()?
+            .reply()
+            .ok()?
+            .value32()
+            .and_then(|mut v| v.next())
+    }
+
+    /// Forward an X11 window's own geometry request to the Qubes daemon
+    /// through the same path `MoveSurfaceGrab`/`ResizeSurfaceGrab` use for
+    /// interactive moves and resizes, rather than duplicating the Configure/ShmImage
+    /// send sequence here. We don't enforce any placement policy of our
+    /// own, so the request is granted outright.
+    fn forward_configure_request(
+        &self,
+        backend_data: &Rc<RefCell<QubesData>>,
+        window: X11Window,
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+    ) {
+        let qubes_window = match self.by_x11_window.get(&window) {
+            Some(id) => *id,
+            None => return,
+        };
+        backend_data
+            .borrow_mut()
+            .process_client_configure(
+                qubes_gui::Configure {
+                    rectangle: qubes_gui::Rectangle {
+                        top_left: qubes_gui::Coordinates { x: x.max(0) as u32, y: y.max(0) as u32 },
+                        size: qubes_gui::WindowSize { width: width.max(1) as u32, height: height.max(1) as u32 },
+                    },
+                    override_redirect: 0,
+                },
+                qubes_window,
+            )
+            .ok();
+        self.configure_window(window, width, height);
+    }
+}
+
+/// Spawn `Xwayland` and, once it signals readiness, the WM connection above.
+/// This is what used to be the bare `state.start_xwayland()` call in
+/// `run_qubes`: starting the server alone gets VM users nothing, since
+/// nothing would ever map an X11 window onto a Qubes GUI window.
+pub fn spawn(
+    handle: &LoopHandle<'static, AnvilState>,
+    display: Rc<RefCell<Display>>,
+    backend_data: Rc<RefCell<QubesData>>,
+    log: ::slog::Logger,
+) {
+    let (xwayland, channel) = XWayland::new(log.clone(), display);
+    let handle_for_ready = handle.clone();
+    let insert_result = handle.insert_source(channel, move |event, _, _state: &mut AnvilState| match event {
+        XWaylandEvent::Ready { connection, client } => {
+            match connect_wm(connection, client, backend_data.clone(), &handle_for_ready, log.clone()) {
+                Ok(()) => info!(log, "XWayland WM connection established"),
+                Err(e) => error!(log, "Failed to set up the XWayland WM connection"; "error" => ?e),
+            }
+        }
+        XWaylandEvent::Exited => warn!(log, "XWayland exited"),
+    });
+    if let Err(e) = insert_result {
+        error!(log, "Failed to insert the XWayland event source"; "error" => ?e);
+        return;
+    }
+    if let Err(e) = xwayland.start(handle.clone()) {
+        error!(log, "Failed to start XWayland"; "error" => ?e);
+    }
+}
+
+/// Once Xwayland is up, connect to it as its window manager: select
+/// `SubstructureRedirect`/`SubstructureNotify` on the root window and wire
+/// the connection's fd into the same calloop that drives everything else.
+fn connect_wm(
+    connection: std::os::unix::net::UnixStream,
+    client: Client,
+    backend_data: Rc<RefCell<QubesData>>,
+    handle: &LoopHandle<'static, AnvilState>,
+    log: ::slog::Logger,
+) -> std::io::Result<()> {
+    let fd = connection.as_raw_fd();
+    let conn = RustConnection::connect_to_stream(connection, 0)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    let root = conn.setup().roots[0].root;
+    let atoms = Atoms::new(&conn)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+        .reply()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    conn.change_window_attributes(
+        root,
+        &ChangeWindowAttributesAux::new().event_mask(
+            EventMask::SUBSTRUCTURE_REDIRECT | EventMask::SUBSTRUCTURE_NOTIFY | EventMask::PROPERTY_CHANGE,
+        ),
+    )
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    conn.flush().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+    let wm = Rc::new(RefCell::new(Wm {
+        conn,
+        atoms,
+        root,
+        client,
+        by_x11_window: HashMap::new(),
+        pending_wl_surfaces: HashMap::new(),
+        log: log.clone(),
+    }));
+
+    handle
+        .insert_source(
+            Generic::from_fd(fd, Interest { readable: true, writable: false }, CalloopMode::Level),
+            move |_readiness, _fd, _state: &mut AnvilState| {
+                dispatch_x11_events(&wm, &backend_data);
+                Ok(PostAction::Continue)
+            },
+        )
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e)))?;
+    Ok(())
+}
+
+/// Drain and handle every X11 event currently queued on the WM connection.
+fn dispatch_x11_events(wm: &Rc<RefCell<Wm>>, backend_data: &Rc<RefCell<QubesData>>) {
+    loop {
+        let event = match wm.borrow().conn.poll_for_event() {
+            Ok(Some(event)) => event,
+            Ok(None) => return,
+            Err(e) => {
+                warn!(wm.borrow().log, "Error reading XWayland WM event"; "error" => ?e);
+                return;
+            }
+        };
+        match event {
+            Event::MapRequest(ev) => handle_map_request(wm, backend_data, ev.window),
+            Event::ConfigureRequest(ev) => wm.borrow().forward_configure_request(
+                backend_data,
+                ev.window,
+                ev.x as i32,
+                ev.y as i32,
+                ev.width as i32,
+                ev.height as i32,
+            ),
+            Event::MapNotify(ev) if ev.override_redirect => {
+                create_qubes_window(wm, backend_data, ev.window, true)
+            }
+            Event::PropertyNotify(ev) if ev.atom == wm.borrow().atoms._NET_WM_NAME => {
+                update_title(wm, backend_data, ev.window)
+            }
+            Event::ClientMessage(ev) if ev.type_ == wm.borrow().atoms.WL_SURFACE_ID => {
+                // The data32[0] field carries the Wayland object id of the
+                // wl_surface this X11 window's client just created for it;
+                // resolve it against the Xwayland server's own client
+                // connection.
+                let surface_id = ev.data.as_data32()[0];
+                let surface = wm.borrow().client.get_resource::<WlSurface>(surface_id);
+                match surface {
+                    Some(surface) => pair_wl_surface(wm, backend_data, ev.window, surface),
+                    None => warn!(
+                        wm.borrow().log,
+                        "WL_SURFACE_ID referenced an unknown wl_surface";
+                        "window" => ev.window, "surface_id" => surface_id
+                    ),
+                }
+            }
+            Event::UnmapNotify(ev) => forget_window(wm, backend_data, ev.window),
+            Event::DestroyNotify(ev) => forget_window(wm, backend_data, ev.window),
+            _ => {}
+        }
+    }
+}
+
+/// A regular (non-override-redirect) client asked to be mapped: actually
+/// map it (we're the WM, so nothing else will), then create the Qubes GUI
+/// window for it.
+fn handle_map_request(wm: &Rc<RefCell<Wm>>, backend_data: &Rc<RefCell<QubesData>>, window: X11Window) {
+    {
+        let wm = wm.borrow();
+        let _ = wm.conn.map_window(window);
+        let _ = wm.conn.flush();
+    }
+    create_qubes_window(wm, backend_data, window, false);
+}
+
+/// Create the Qubes GUI window shadowing `window`, reading its geometry,
+/// `WM_TRANSIENT_FOR` and title as a one-time snapshot (later title changes
+/// arrive through `PropertyNotify`, handled by `update_title`).
+fn create_qubes_window(
+    wm: &Rc<RefCell<Wm>>,
+    backend_data: &Rc<RefCell<QubesData>>,
+    window: X11Window,
+    override_redirect: bool,
+) {
+    if wm.borrow().by_x11_window.contains_key(&window) {
+        return;
+    }
+    let (x, y, width, height, parent, title) = {
+        let wm_ref = wm.borrow();
+        let geometry = wm_ref.conn.get_geometry(window).ok().and_then(|c| c.reply().ok());
+        let (x, y, width, height) = geometry
+            .map(|g| (g.x as i32, g.y as i32, g.width as i32, g.height as i32))
+            .unwrap_or((0, 0, 1, 1));
+        let parent = wm_ref
+            .transient_for(window)
+            .and_then(|parent_window| wm_ref.by_x11_window.get(&parent_window).copied());
+        let title = wm_ref.read_title(window);
+        (x, y, width, height, parent, title)
+    };
+
+    let mut backend = backend_data.borrow_mut();
+    let id = backend.id();
+    backend.map.insert(
+        id,
+        QubesBackendData {
+            surface: Kind::X11(X11Surface {
+                window,
+                wm: wm.clone(),
+                pending_size: Cell::new(None),
+                wl_surface: OnceCell::new(),
+            }),
+            has_configured: false,
+            coordinates: (x, y).into(),
+            needs_frame: false,
+            last_presented: None,
+        },
+    );
+    wm.borrow_mut().by_x11_window.insert(window, id);
+    let pending_surface = wm.borrow_mut().pending_wl_surfaces.remove(&window);
+
+    let msg = qubes_gui::Create {
+        rectangle: qubes_gui::Rectangle {
+            top_left: qubes_gui::Coordinates { x: x.max(0) as u32, y: y.max(0) as u32 },
+            size: qubes_gui::WindowSize { width: width.max(1) as u32, height: height.max(1) as u32 },
+        },
+        parent: parent.map(Into::into),
+        override_redirect: override_redirect as u32,
+    };
+    debug!(backend.log, "Creating XWayland window {} (X11 id {})", id, window);
+    backend.agent.client().send(&msg, id).expect("TODO: send errors");
+    let configure_msg = qubes_gui::Configure {
+        rectangle: msg.rectangle,
+        override_redirect: msg.override_redirect,
+    };
+    backend.agent.client().send(&configure_msg, id).expect("TODO: send errors");
+    backend
+        .agent
+        .client()
+        .send(
+            &qubes_gui::MapInfo {
+                override_redirect: msg.override_redirect,
+                transient_for: parent.map(u32::from).unwrap_or(0),
+            },
+            id,
+        )
+        .unwrap();
+    if let Some(title) = title {
+        send_title(&mut backend, id, &title);
+    }
+    drop(backend);
+    // A WL_SURFACE_ID for this window may have arrived before MapRequest
+    // did; finish that pairing now that the Qubes window it needs exists.
+    if let Some(surface) = pending_surface {
+        finish_pairing(backend_data, id, surface);
+    }
+}
+
+/// Pair `surface` with the `X11Surface` tracking `window`, if its Qubes GUI
+/// window already exists; otherwise stash it until `create_qubes_window`
+/// catches up, since `WL_SURFACE_ID` can race `MapRequest`.
+fn pair_wl_surface(
+    wm: &Rc<RefCell<Wm>>,
+    backend_data: &Rc<RefCell<QubesData>>,
+    window: X11Window,
+    surface: WlSurface,
+) {
+    let id = match wm.borrow().by_x11_window.get(&window).copied() {
+        Some(id) => id,
+        None => {
+            wm.borrow_mut().pending_wl_surfaces.insert(window, surface);
+            return;
+        }
+    };
+    finish_pairing(backend_data, id, surface);
+}
+
+/// Bind the shared buffer/damage pipeline in `shell.rs` to this window by
+/// giving its wl_surface the same `SurfaceData` (keyed by the same Qubes
+/// window id) every xdg_shell/wl_shell surface gets at creation time, then
+/// record the wl_surface on the `X11Surface` itself so `Kind::get_surface`
+/// can hand it back.
+fn finish_pairing(backend_data: &Rc<RefCell<QubesData>>, id: NonZeroU32, surface: WlSurface) {
+    let mut backend = backend_data.borrow_mut();
+    if let Some(QubesBackendData { surface: Kind::X11(x11), .. }) = backend.map.get(&id) {
+        if x11.wl_surface.set(surface.clone()).is_err() {
+            warn!(backend.log, "X11 window was already paired with a wl_surface"; "window" => u32::from(id));
+            return;
+        }
+    } else {
+        return;
+    }
+    let _ = smithay::wayland::compositor::with_states(&surface, |data| {
+        data.data_map.insert_if_missing::<RefCell<SurfaceData>, _>(|| {
+            RefCell::new(SurfaceData {
+                buffer: None,
+                geometry: None,
+                buffer_dimensions: None,
+                buffer_scale: 0,
+                window: id,
+                buffer_swapped: false,
+                coordinates: Default::default(),
+                pre_fullscreen_size: None,
+                minimized: false,
+                last_title: None,
+            })
+        });
+    });
+    debug!(backend.log, "Paired XWayland window with its wl_surface"; "window" => u32::from(id));
+}
+
+fn send_title(backend: &mut QubesData, id: NonZeroU32, title: &str) {
+    let bytes = title.as_bytes();
+    let mut buf = [0u8; 128];
+    buf[..bytes.len().min(128)].copy_from_slice(&bytes[..bytes.len().min(128)]);
+    backend.agent.send_raw(&mut buf, id, qubes_gui::MSG_SET_TITLE).unwrap();
+}
+
+fn update_title(wm: &Rc<RefCell<Wm>>, backend_data: &Rc<RefCell<QubesData>>, window: X11Window) {
+    let id = match wm.borrow().by_x11_window.get(&window).copied() {
+        Some(id) => id,
+        None => return,
+    };
+    if let Some(title) = wm.borrow().read_title(window) {
+        send_title(&mut backend_data.borrow_mut(), id, &title);
+    }
+}
+
+/// An X11 window was unmapped or destroyed: reuse the same reaping path the
+/// redraw timer uses for dead Wayland surfaces.
+fn forget_window(wm: &Rc<RefCell<Wm>>, backend_data: &Rc<RefCell<QubesData>>, window: X11Window) {
+    let id = wm.borrow_mut().by_x11_window.remove(&window);
+    // A WL_SURFACE_ID stashed for this window is only useful once
+    // `create_qubes_window` consumes it; if the window goes away first
+    // (withdrawn or destroyed without ever mapping), drop it too, rather
+    // than leaking a WlSurface reference for the lifetime of the WM.
+    wm.borrow_mut().pending_wl_surfaces.remove(&window);
+    if let Some(id) = id {
+        backend_data.borrow_mut().reap_window(id);
+    }
+}
+
+/// Called from `shell.rs`'s surface-commit handler for every client,
+/// XWayland's included. It does nothing: wl_surfaces are paired with their
+/// `X11Surface` through the `WL_SURFACE_ID` client message
+/// (`pair_wl_surface`), not on commit.
+pub fn commit_hook(_surface: &smithay::reexports::wayland_server::protocol::wl_surface::WlSurface) {}